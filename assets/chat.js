@@ -3,13 +3,19 @@ let messageCache = new Map();
 let ws = null;
 let reconnectAttempts = 0;
 let selectedMessageId = null;
+let currentChatId = 'default';
+let clientId = null;
+let nextRequestId = 1;
+const PROTOCOL_VERSIONS = [1];
 const MAX_RECONNECT_ATTEMPTS = 5;
+const SYNC_INTERVAL_MS = 2000;
 const WEBSOCKET_URL = 'ws://localhost:{{WEBSOCKET_PORT}}/';
 
 // UI Elements
 const messageInput = document.getElementById('messageInput');
 const messageArea = document.getElementById('messageArea');
 const loadingOverlay = document.getElementById('messageLoading');
+const chatSelect = document.getElementById('chatSelect');
 
 // Auto-resize textarea
 function adjustTextareaHeight() {
@@ -50,14 +56,12 @@ function connectWebSocket() {
         console.log('WebSocket connected');
         updateConnectionStatus('connected');
         reconnectAttempts = 0;
-        // Request initial messages
-        sendWebSocketMessage({
-            type: 'get_messages'
-        });
+        // The chat list is requested once the server assigns a client id
     };
     
     ws.onclose = () => {
         console.log('WebSocket disconnected');
+        clientId = null;
         updateConnectionStatus('disconnected');
         if (reconnectAttempts < MAX_RECONNECT_ATTEMPTS) {
             reconnectAttempts++;
@@ -82,7 +86,14 @@ function connectWebSocket() {
 
 function sendWebSocketMessage(message) {
     if (ws && ws.readyState === WebSocket.OPEN) {
-        ws.send(JSON.stringify(message));
+        const envelope = {
+            chat_id: currentChatId,
+            request_id: nextRequestId++,
+            sent_at: Date.now(),
+            ...message
+        };
+        if (clientId) envelope.client_id = clientId;
+        ws.send(JSON.stringify(envelope));
     } else {
         console.warn('WebSocket not connected');
         updateConnectionStatus('disconnected');
@@ -90,6 +101,59 @@ function sendWebSocketMessage(message) {
 }
 
 function handleWebSocketMessage(data) {
+    if (data.type === 'connected') {
+        clientId = data.client_id;
+        sendWebSocketMessage({
+            type: 'hello',
+            protocol_versions: PROTOCOL_VERSIONS
+        });
+        // Request the chat list, which then loads the current chat's messages
+        sendWebSocketMessage({
+            type: 'list_chats'
+        });
+        return;
+    }
+
+    if (data.type === 'hello') {
+        console.log('Using protocol version', data.protocol_version);
+        return;
+    }
+
+    if (data.type === 'resync') {
+        // Too many updates were missed, so reload everything
+        messageCache.clear();
+        sendWebSocketMessage({
+            type: 'list_chats'
+        });
+        return;
+    }
+
+    if (data.type === 'error') {
+        console.error(`Server error (${data.code}):`, data.message);
+        // Drop placeholders and reload, since part of the exchange may have been stored
+        for (const id of [...messageCache.keys()]) {
+            if (id.startsWith('temp-')) {
+                messageCache.delete(id);
+            }
+        }
+        renderMessages([...messageCache.values()], false);
+        sendWebSocketMessage({
+            type: 'get_messages'
+        });
+        alert(data.retry_after
+            ? `${data.message} (try again in ${data.retry_after}s)`
+            : data.message);
+        return;
+    }
+
+    if (data.type === 'chat_list' && data.chats) {
+        renderChatList(data.chats, data.chat_id);
+        return;
+    }
+
+    // Ignore updates for chats other than the one on screen
+    if (data.chat_id && data.chat_id !== currentChatId) return;
+
     if (data.type === 'message_update' && data.messages) {
         // Update message cache with new messages
         data.messages.forEach(msg => {
@@ -108,9 +172,64 @@ function handleWebSocketMessage(data) {
         
         // Update head ID if present
         updateHeadId(Array.from(messageCache.values()));
+    } else if (data.type === 'message_complete' && data.message) {
+        messageCache.set(data.message.id, data.message);
+
+        renderMessages(Array.from(messageCache.values()), false);
+        updateHeadId(Array.from(messageCache.values()));
+    } else if (data.type === 'branch_update' && data.messages) {
+        // The head moved to another branch, so the cache only holds that branch
+        messageCache.clear();
+        data.messages.forEach(msg => {
+            messageCache.set(msg.id, msg);
+        });
+
+        renderMessages(Array.from(messageCache.values()), false);
+        updateHeadId(Array.from(messageCache.values()));
     }
 }
 
+// Chat management
+function renderChatList(chats, selectChatId) {
+    if (selectChatId) {
+        currentChatId = selectChatId;
+    }
+    // Fall back to the first chat when the current one was deleted
+    if (!chats.some(chat => chat.id === currentChatId) && chats.length > 0) {
+        currentChatId = chats[0].id;
+    }
+
+    chatSelect.innerHTML = chats.map(chat => `
+        <option value="${escapeHtml(chat.id)}" ${chat.id === currentChatId ? 'selected' : ''}>
+            ${escapeHtml(chat.title)}
+        </option>
+    `).join('');
+
+    switchChat(currentChatId);
+}
+
+function switchChat(chatId) {
+    currentChatId = chatId;
+    selectedMessageId = null;
+    messageCache.clear();
+    renderMessages([], false);
+    sendWebSocketMessage({
+        type: 'get_messages'
+    });
+}
+
+function createChat() {
+    const title = prompt('Chat title', 'New chat');
+    if (title === null || !title.trim()) return;
+
+    sendWebSocketMessage({
+        type: 'create_chat',
+        title: title.trim()
+    });
+}
+
+chatSelect.addEventListener('change', () => switchChat(chatSelect.value));
+
 // Update head ID in title
 function updateHeadId(messages) {
     const headElement = document.querySelector('.head-id');
@@ -122,12 +241,72 @@ function updateHeadId(messages) {
     }
 }
 
+// Attachments
+const fileInput = document.getElementById('fileInput');
+const attachmentList = document.getElementById('attachmentList');
+// Uploaded files waiting to go out with the next message
+let pendingAttachments = [];
+
+fileInput.addEventListener('change', () => {
+    uploadFiles([...fileInput.files]);
+    fileInput.value = '';
+});
+
+async function uploadFiles(files) {
+    for (const file of files) {
+        try {
+            const response = await fetch('/api/attachments', {
+                method: 'POST',
+                headers: { 'Content-Type': file.type || 'application/octet-stream' },
+                body: file
+            });
+            const result = await response.json();
+            if (!response.ok) {
+                throw new Error(result.message || `HTTP ${response.status}`);
+            }
+            pendingAttachments.push({ ...result.attachment, name: file.name });
+        } catch (error) {
+            console.error('Error uploading attachment:', error);
+            alert(`Failed to upload ${file.name}: ${error.message}`);
+        }
+    }
+    renderAttachmentList();
+}
+
+function renderAttachmentList() {
+    attachmentList.innerHTML = pendingAttachments.map((attachment, index) => `
+        <span class="attachment-chip">
+            ${escapeHtml(attachment.name)}
+            <button class="attachment-remove" data-index="${index}" title="Remove">×</button>
+        </span>
+    `).join('');
+    attachmentList.querySelectorAll('.attachment-remove').forEach(button => {
+        button.addEventListener('click', () => {
+            pendingAttachments.splice(Number(button.dataset.index), 1);
+            renderAttachmentList();
+        });
+    });
+}
+
+// Images become image blocks, anything else a document titled with its file name
+function attachmentBlock(attachment) {
+    const source = { type: 'attachment', attachment_id: attachment.attachment_id };
+    return attachment.media_type.startsWith('image/')
+        ? { type: 'image', source }
+        : { type: 'document', source, title: attachment.name };
+}
+
 // Message handling
 async function sendMessage() {
     const text = messageInput.value.trim();
     const sendButton = document.querySelector('.send-button');
 
-    if (!text) return;
+    if (!text && pendingAttachments.length === 0) return;
+
+    // Plain text unless files are attached
+    const content = pendingAttachments.length
+        ? [...pendingAttachments.map(attachmentBlock), ...(text ? [{ type: 'text', text }] : [])]
+        : text;
 
     try {
         messageInput.disabled = true;
@@ -136,7 +315,7 @@ async function sendMessage() {
         // Create and show user message immediately
         const userMsg = {
             role: 'user',
-            content: text,
+            content,
             id: 'temp-' + Date.now(),
             parent: null
         };
@@ -148,9 +327,11 @@ async function sendMessage() {
         // Send message to server
         sendWebSocketMessage({
             type: 'send_message',
-            content: text
+            content
         });
 
+        pendingAttachments = [];
+        renderAttachmentList();
         messageInput.value = '';
         messageInput.style.height = '2.5rem';
         messageInput.focus();
@@ -200,6 +381,115 @@ function copyMessageId(messageId, button) {
         });
 }
 
+function regenerateMessage(messageId) {
+    renderMessages([...messageCache.values()], true);
+    sendWebSocketMessage({
+        type: 'regenerate_message',
+        message_id: messageId
+    });
+}
+
+function editMessage(messageId) {
+    const original = messageCache.get(messageId);
+    const text = prompt('Edit message', original ? messageText(original) : '');
+    if (text === null || !text.trim()) return;
+
+    // Images and documents stay attached; only the text is replaced
+    const images = original
+        ? contentBlocks(original).filter(block => block.type === 'image' || block.type === 'document')
+        : [];
+    renderMessages([...messageCache.values()], true);
+    sendWebSocketMessage({
+        type: 'edit_message',
+        message_id: messageId,
+        content: images.length ? [...images, { type: 'text', text: text.trim() }] : text.trim()
+    });
+}
+
+// When a message was written and, for replies, which model wrote it
+function formatMessageMeta(msg) {
+    const meta = msg.metadata || {};
+    const parts = [];
+    if (meta.created_at) {
+        parts.push(new Date(meta.created_at).toLocaleString());
+    }
+    if (meta.model) {
+        parts.push(meta.model);
+    }
+    if (parts.length === 0) return '';
+    return `<div class="message-meta">${escapeHtml(parts.join(' · '))}</div>`;
+}
+
+// Content is a plain string or an array of blocks
+function contentBlocks(msg) {
+    return typeof msg.content === 'string'
+        ? [{ type: 'text', text: msg.content }]
+        : (msg.content || []);
+}
+
+function messageText(msg) {
+    return contentBlocks(msg)
+        .filter(block => block.type === 'text')
+        .map(block => block.text)
+        .join('\n');
+}
+
+function sourceUrl(source) {
+    switch (source.type) {
+        case 'attachment':
+            return `/api/attachments/${encodeURIComponent(source.attachment_id)}`;
+        case 'base64':
+            return `data:${source.media_type};base64,${source.data}`;
+        case 'url':
+            return source.url;
+        default:
+            return null;
+    }
+}
+
+function formatBlock(block) {
+    switch (block.type) {
+        case 'text':
+            return block.text ? formatMessage(block.text) : '';
+        case 'image': {
+            const src = sourceUrl(block.source || {});
+            return src ? `<img class="message-image" src="${escapeHtml(src)}" alt="Image">` : '';
+        }
+        case 'document': {
+            const href = sourceUrl(block.source || {});
+            const title = escapeHtml(block.title || 'Document');
+            return href
+                ? `<a class="attachment-chip" href="${escapeHtml(href)}" target="_blank">${title}</a>`
+                : `<span class="attachment-chip">${title}</span>`;
+        }
+        case 'thinking':
+            return `
+                <details class="thinking">
+                    <summary>Thinking</summary>
+                    ${formatMessage(block.thinking)}
+                </details>
+            `;
+        case 'tool_use':
+            return `
+                <div class="tool-call">
+                    <div class="tool-call-name">Calls ${escapeHtml(block.name)}</div>
+                    <pre><code>${escapeHtml(JSON.stringify(block.input, null, 2))}</code></pre>
+                </div>
+            `;
+        case 'tool_result':
+            return `
+                <div class="tool-call-name">${escapeHtml(block.name)} ${block.is_error ? 'failed' : 'output'}</div>
+                ${formatMessage(block.content)}
+            `;
+        default:
+            return '';
+    }
+}
+
+function formatContent(msg) {
+    return contentBlocks(msg).map(formatBlock).join('');
+}
+
 function renderMessages(messages, isTyping = false) {
     // Sort messages by their sequence in the chat
     const sortedMessages = messages.sort((a, b) => {
@@ -223,7 +513,8 @@ function renderMessages(messages, isTyping = false) {
             ${sortedMessages.map(msg => `
                 <div class="message ${msg.role} ${msg.id === selectedMessageId ? 'selected' : ''}" 
                      data-id="${msg.id}">
-                    ${formatMessage(msg.content)}
+                    ${formatContent(msg)}
+                    ${formatMessageMeta(msg)}
                     <div class="message-actions">
                         <button class="message-action-button copy-button">
                             <svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 24 24" fill="none" stroke="currentColor">
@@ -231,6 +522,12 @@ function renderMessages(messages, isTyping = false) {
                             </svg>
                             Copy ID
                         </button>
+                        ${msg.role === 'assistant' ? `
+                            <button class="message-action-button regenerate-button">Regenerate</button>
+                        ` : ''}
+                        ${msg.role === 'user' ? `
+                            <button class="message-action-button edit-button">Edit</button>
+                        ` : ''}
                     </div>
                 </div>
             `).join('')}
@@ -248,6 +545,8 @@ function renderMessages(messages, isTyping = false) {
     messageArea.querySelectorAll('.message').forEach(messageElement => {
         const messageId = messageElement.dataset.id;
         const copyButton = messageElement.querySelector('.copy-button');
+        const regenerateButton = messageElement.querySelector('.regenerate-button');
+        const editButton = messageElement.querySelector('.edit-button');
         
         // Message click event
         messageElement.addEventListener('click', handleMessageClick);
@@ -259,6 +558,20 @@ function renderMessages(messages, isTyping = false) {
                 copyMessageId(messageId, copyButton);
             });
         }
+
+        if (regenerateButton) {
+            regenerateButton.addEventListener('click', (event) => {
+                event.stopPropagation();
+                regenerateMessage(messageId);
+            });
+        }
+
+        if (editButton) {
+            editButton.addEventListener('click', (event) => {
+                event.stopPropagation();
+                editMessage(messageId);
+            });
+        }
     });
 
     messageArea.scrollTop = messageArea.scrollHeight;
@@ -318,6 +631,15 @@ document.addEventListener('DOMContentLoaded', () => {
     });
 });
 
+// Poll for updates made by other clients
+setInterval(() => {
+    if (clientId && ws && ws.readyState === WebSocket.OPEN) {
+        sendWebSocketMessage({
+            type: 'sync'
+        });
+    }
+}, SYNC_INTERVAL_MS);
+
 // Handle visibility changes
 document.addEventListener('visibilitychange', () => {
     if (!document.hidden && (!ws || ws.readyState !== WebSocket.OPEN)) {
@@ -328,6 +650,7 @@ document.addEventListener('visibilitychange', () => {
 // Cleanup on page unload
 window.addEventListener('unload', () => {
     if (ws) {
-        ws.close();
+        // The close reason tells the server which client left
+        ws.close(1000, clientId || '');
     }
 });
\ No newline at end of file
@@ -0,0 +1,118 @@
+use crate::error::ChatError;
+use crate::store;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+// Largest upload accepted; Anthropic rejects bigger images anyway
+pub const MAX_ATTACHMENT_BYTES: usize = 5 * 1024 * 1024;
+
+// Image formats every provider accepts
+const IMAGE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+// Text is only ever kept as plain text, so a declared `text/html` or similar
+// can't make the browser render an upload when it is downloaded
+const TEXT_TYPE: &str = "text/plain";
+
+// An uploaded file as kept in the message store. Its id is the SHA-1 of this
+// record serialized, so the same bytes uploaded twice share one entry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attachment {
+    pub media_type: String,
+    // The file's bytes, base64 encoded so the record is text like a message
+    pub data: String,
+}
+
+// What clients get back after an upload
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttachmentInfo {
+    pub attachment_id: String,
+    pub media_type: String,
+    pub size: usize,
+}
+
+impl Attachment {
+    // Checks an upload and settles its media type: a declared type is used when
+    // it's one we accept, otherwise the bytes decide (other `text/*` types
+    // included, which come out as plain text)
+    pub fn new(bytes: &[u8], declared: Option<&str>) -> Result<Self, ChatError> {
+        if bytes.is_empty() {
+            return Err(ChatError::validation("Attachment is empty"));
+        }
+        if bytes.len() > MAX_ATTACHMENT_BYTES {
+            return Err(ChatError::validation(format!(
+                "Attachment is {} bytes; the limit is {}",
+                bytes.len(),
+                MAX_ATTACHMENT_BYTES
+            )));
+        }
+
+        let declared = declared
+            .map(|media_type| {
+                media_type
+                    .split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_ascii_lowercase()
+            })
+            .filter(|media_type| {
+                IMAGE_TYPES.contains(&media_type.as_str())
+                    || media_type == "application/pdf"
+                    || media_type == TEXT_TYPE
+            });
+        let media_type = declared
+            .or_else(|| sniff(bytes).map(str::to_string))
+            .ok_or_else(|| {
+                ChatError::validation(
+                    "Unsupported attachment; expected a PNG, JPEG, GIF or WebP image, a PDF or UTF-8 text",
+                )
+            })?;
+        if media_type == TEXT_TYPE && std::str::from_utf8(bytes).is_err() {
+            return Err(ChatError::validation("Text attachment is not valid UTF-8"));
+        }
+
+        Ok(Self {
+            media_type,
+            data: STANDARD.encode(bytes),
+        })
+    }
+
+    pub fn is_image(&self) -> bool {
+        IMAGE_TYPES.contains(&self.media_type.as_str())
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.media_type.starts_with("text/")
+    }
+
+    pub fn bytes(&self) -> Result<Vec<u8>, ChatError> {
+        STANDARD.decode(&self.data).map_err(ChatError::store)
+    }
+
+    // The record as stored, with the id it is stored under
+    pub fn encode(&self) -> Result<(String, Vec<u8>), ChatError> {
+        let bytes = serde_json::to_vec(self).map_err(ChatError::store)?;
+        Ok((store::sha1_hex(&bytes), bytes))
+    }
+}
+
+// Recognises the accepted formats by their leading bytes, falling back to
+// plain text for anything that decodes as UTF-8
+fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if bytes.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else if std::str::from_utf8(bytes).is_ok() {
+        Some(TEXT_TYPE)
+    } else {
+        None
+    }
+}
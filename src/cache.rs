@@ -0,0 +1,127 @@
+use crate::Message;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+// Messages loaded or saved recently
+const MESSAGE_CAPACITY: usize = 1024;
+// Materialized branch histories, keyed by their head
+const HISTORY_CAPACITY: usize = 16;
+
+// Messages are content-addressed, so whatever is stored under an id never
+// changes and cached copies never go stale; a history keyed by its head id is
+// likewise fixed, and a moved head simply looks up a different entry. Like
+// secrets, the caches live in instance memory only and start empty after a
+// restart.
+thread_local! {
+    static MESSAGES: RefCell<Lru<Message>> = RefCell::new(Lru::new(MESSAGE_CAPACITY));
+    static HISTORIES: RefCell<Lru<Vec<Message>>> = RefCell::new(Lru::new(HISTORY_CAPACITY));
+}
+
+pub fn message(id: &str) -> Option<Message> {
+    MESSAGES.with(|messages| messages.borrow_mut().get(id).cloned())
+}
+
+pub fn remember_message(msg: &Message) {
+    if let Some(id) = &msg.id {
+        MESSAGES.with(|messages| messages.borrow_mut().insert(id.clone(), msg.clone()));
+    }
+}
+
+// Oldest-first history of the branch ending at `head`
+pub fn history(head: &str) -> Option<Vec<Message>> {
+    HISTORIES.with(|histories| histories.borrow_mut().get(head).cloned())
+}
+
+pub fn remember_history(head: &str, history: &[Message]) {
+    HISTORIES.with(|histories| {
+        histories
+            .borrow_mut()
+            .insert(head.to_string(), history.to_vec())
+    });
+}
+
+// Least-recently-used map from ids to values
+struct Lru<V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<String, (u64, V)>,
+    // Last use of each entry, oldest first
+    order: BTreeMap<u64, String>,
+}
+
+impl<V> Lru<V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        self.tick += 1;
+        let (used, value) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        self.order.insert(self.tick, key.to_string());
+        *used = self.tick;
+        Some(value)
+    }
+
+    fn insert(&mut self, key: String, value: V) {
+        self.tick += 1;
+        if let Some((used, _)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+        } else if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(lru: &Lru<u32>) -> Vec<&str> {
+        lru.order.values().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn evicts_the_least_recently_inserted_entry() {
+        let mut lru = Lru::new(2);
+        lru.insert("a".to_string(), 1);
+        lru.insert("b".to_string(), 2);
+        lru.insert("c".to_string(), 3);
+        assert!(lru.get("a").is_none());
+        assert_eq!(keys(&lru), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn a_read_keeps_an_entry() {
+        let mut lru = Lru::new(2);
+        lru.insert("a".to_string(), 1);
+        lru.insert("b".to_string(), 2);
+        assert_eq!(lru.get("a"), Some(&1));
+        lru.insert("c".to_string(), 3);
+        assert!(lru.get("b").is_none());
+        assert_eq!(keys(&lru), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn reinserting_replaces_the_value_and_refreshes_the_entry() {
+        let mut lru = Lru::new(2);
+        lru.insert("a".to_string(), 1);
+        lru.insert("b".to_string(), 2);
+        lru.insert("a".to_string(), 10);
+        assert_eq!(lru.entries.len(), 2);
+        assert_eq!(lru.order.len(), 2);
+        lru.insert("c".to_string(), 3);
+        assert!(lru.get("b").is_none());
+        assert_eq!(lru.get("a"), Some(&10));
+        assert_eq!(keys(&lru), vec!["c", "a"]);
+    }
+}
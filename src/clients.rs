@@ -0,0 +1,108 @@
+use crate::protocol::ServerEvent;
+use crate::ChatId;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Frames a client can fall behind by before it is told to reload instead
+const MAX_OUTBOX: usize = 200;
+// WebSocket events handled after a client was last heard from before it is
+// dropped; clients poll with `sync`, so only ones that went away get this far
+const IDLE_LIMIT: u64 = 300;
+
+// A WebSocket connection, identified by the id handed out on connect.
+//
+// The websocket-server interface only lets the actor answer the connection
+// that sent an event, so frames for other clients wait in their outbox until
+// that client's next command (or `sync` poll) picks them up.
+#[derive(Debug, Clone, Default)]
+struct Client {
+    // Chat the client last acted on, which decides the updates it receives
+    chat_id: Option<ChatId>,
+    outbox: Vec<Value>,
+    // Event count when the client last sent anything
+    last_seen: u64,
+}
+
+impl Client {
+    fn push(&mut self, frame: Value) {
+        if self.outbox.len() >= MAX_OUTBOX {
+            self.outbox.clear();
+            self.outbox.push(ServerEvent::Resync.to_frame(None));
+            return;
+        }
+        self.outbox.push(frame);
+    }
+}
+
+#[derive(Default)]
+struct Clients {
+    // Counts WebSocket events; the actor has no clock, so idleness is measured
+    // in other clients' activity
+    tick: u64,
+    clients: HashMap<String, Client>,
+}
+
+// Like the caches, connections live in instance memory only. Connections don't
+// survive a restart either, and keeping outboxes out of the persisted state
+// means polling and broadcasting never rewrite it.
+thread_local! {
+    static CLIENTS: RefCell<Clients> = RefCell::new(Clients::default());
+}
+
+pub fn connect(client_id: &str) {
+    CLIENTS.with(|clients| {
+        let mut clients = clients.borrow_mut();
+        clients.tick += 1;
+        let last_seen = clients.tick;
+        clients.clients.insert(
+            client_id.to_string(),
+            Client {
+                last_seen,
+                ..Client::default()
+            },
+        );
+    });
+}
+
+// Whether the client was still known
+pub fn disconnect(client_id: &str) -> bool {
+    CLIENTS.with(|clients| clients.borrow_mut().clients.remove(client_id).is_some())
+}
+
+// Notes that a client is still there, and what it is viewing, and hands back
+// the frames queued for it. Clients idle for too long are dropped on the way.
+pub fn check_in(client_id: &str, chat_id: Option<&str>) -> Vec<Value> {
+    CLIENTS.with(|clients| {
+        let mut clients = clients.borrow_mut();
+        clients.tick += 1;
+        let tick = clients.tick;
+        clients
+            .clients
+            .retain(|_, client| tick - client.last_seen <= IDLE_LIMIT);
+
+        // A client that was dropped or connected before a restart starts afresh
+        let client = clients.clients.entry(client_id.to_string()).or_default();
+        client.last_seen = tick;
+        if let Some(chat_id) = chat_id {
+            client.chat_id = Some(chat_id.to_string());
+        }
+        std::mem::take(&mut client.outbox)
+    })
+}
+
+// Queues `frame` for every client except `sender`; with a `chat_id`, only
+// for clients currently viewing that chat
+pub fn broadcast(sender: Option<&str>, chat_id: Option<&str>, frame: &Value) {
+    CLIENTS.with(|clients| {
+        for (client_id, client) in clients.borrow_mut().clients.iter_mut() {
+            if Some(client_id.as_str()) == sender {
+                continue;
+            }
+            if chat_id.is_some() && client.chat_id.as_deref() != chat_id {
+                continue;
+            }
+            client.push(frame.clone());
+        }
+    });
+}
@@ -0,0 +1,155 @@
+use crate::error::ChatError;
+use crate::tools::ToolCall;
+use serde::de::Deserializer;
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// One part of a message. Providers get these translated into their own
+// request format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: MediaSource,
+    },
+    // A PDF or text file
+    Document {
+        source: MediaSource,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+    },
+    // The model's reasoning, sent back unchanged on later turns
+    Thinking {
+        thinking: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        // Tool that produced the output, for display
+        name: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        is_error: bool,
+    },
+}
+
+// Where an image's or document's bytes come from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MediaSource {
+    Base64 { media_type: String, data: String },
+    // A plain-text document's contents
+    Text { media_type: String, data: String },
+    Url { url: String },
+    // A file uploaded earlier, swapped for its contents when sent to a provider
+    Attachment { attachment_id: String },
+}
+
+// A message's blocks. A lone text block is stored as a plain string, which is
+// how every message looked before blocks existed, so their ids still match.
+#[derive(Debug, Clone, Default)]
+pub struct Content(pub Vec<ContentBlock>);
+
+impl Content {
+    pub fn from_text(text: impl Into<String>) -> Self {
+        Self(vec![ContentBlock::Text { text: text.into() }])
+    }
+
+    // The text blocks, joined
+    pub fn text(&self) -> String {
+        self.0
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Every piece of text the blocks hold, including tool inputs and outputs,
+    // for searching and estimating size
+    pub fn full_text(&self) -> String {
+        self.0
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text.clone()),
+                ContentBlock::Thinking { thinking, .. } => Some(thinking.clone()),
+                ContentBlock::ToolUse { name, input, .. } => Some(format!("{} {}", name, input)),
+                ContentBlock::ToolResult { content, .. } => Some(content.clone()),
+                ContentBlock::Document { title, .. } => title.clone(),
+                ContentBlock::Image { .. } => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn tool_calls(&self) -> Vec<ToolCall> {
+        self.0
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::ToolUse { id, name, input } => Some(ToolCall {
+                    id: id.clone(),
+                    name: name.clone(),
+                    input: input.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // What a client may write as a user message: text, images and documents
+    pub fn validate_user(&self) -> Result<(), ChatError> {
+        if self.0.is_empty() {
+            return Err(ChatError::validation("Message content must not be empty"));
+        }
+        for block in &self.0 {
+            if !matches!(
+                block,
+                ContentBlock::Text { .. }
+                    | ContentBlock::Image { .. }
+                    | ContentBlock::Document { .. }
+            ) {
+                return Err(ChatError::validation(
+                    "User messages may only hold text, image and document blocks",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Content {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.as_slice() {
+            [ContentBlock::Text { text }] => serializer.serialize_str(text),
+            blocks => blocks.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Content {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Text(String),
+            Blocks(Vec<ContentBlock>),
+        }
+
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Text(text) => Self::from_text(text),
+            Stored::Blocks(blocks) => Self(blocks),
+        })
+    }
+}
@@ -0,0 +1,220 @@
+use crate::Message;
+use serde::{Deserialize, Serialize};
+
+// How much of a branch's history is sent with each request
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContextStrategy {
+    // Every message on the branch
+    #[default]
+    Full,
+    // Only the most recent `count` messages
+    LastN {
+        count: usize,
+    },
+    // The most recent messages that fit in `max_tokens`
+    TokenBudget {
+        max_tokens: u64,
+    },
+    // Older messages are folded into a stored summary once the history
+    // outgrows `max_tokens`, keeping the last `keep_recent` messages verbatim
+    Summary {
+        max_tokens: u64,
+        keep_recent: usize,
+    },
+}
+
+impl ContextStrategy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::LastN { .. } => "last_n",
+            Self::TokenBudget { .. } => "token_budget",
+            Self::Summary { .. } => "summary",
+        }
+    }
+}
+
+// What was actually sent for an assistant reply
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContextInfo {
+    pub strategy: String,
+    pub messages_sent: usize,
+    pub messages_omitted: usize,
+    pub estimated_tokens: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary_id: Option<String>,
+}
+
+// Rough count at four bytes per token plus some per-message overhead; there
+// is no tokenizer available inside the actor
+pub fn estimate_tokens(text: &str) -> u64 {
+    text.len() as u64 / 4 + 4
+}
+
+pub fn estimate_history(messages: &[Message]) -> u64 {
+    messages
+        .iter()
+        .map(|msg| estimate_tokens(&msg.content.full_text()))
+        .sum()
+}
+
+// Most recent messages, at most `count` of them
+pub fn last_n(messages: &[Message], count: usize) -> &[Message] {
+    recent_turns(messages, |recent| recent.len() <= count)
+}
+
+// Newest messages whose estimated size fits the budget
+pub fn within_budget(messages: &[Message], max_tokens: u64) -> &[Message] {
+    recent_turns(messages, |recent| estimate_history(recent) <= max_tokens)
+}
+
+// The longest run of whole turns at the end of `messages` that `fits`. A turn
+// starts at a user message and runs through the replies, tool calls and tool
+// results that answer it, so a call is never sent without its result or the
+// reverse, and the request opens with a user turn as providers expect. The
+// latest turn is kept even when it doesn't fit, so there is something to answer.
+fn recent_turns(messages: &[Message], fits: impl Fn(&[Message]) -> bool) -> &[Message] {
+    let starts = turn_starts(messages);
+    let start = starts
+        .iter()
+        .copied()
+        .find(|&start| fits(&messages[start..]))
+        .or(starts.last().copied())
+        .unwrap_or(messages.len());
+    &messages[start..]
+}
+
+// The history from its first user turn on
+pub fn from_first_turn(messages: &[Message]) -> &[Message] {
+    let start = turn_starts(messages)
+        .first()
+        .copied()
+        .unwrap_or(messages.len());
+    &messages[start..]
+}
+
+fn turn_starts(messages: &[Message]) -> Vec<usize> {
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(_, msg)| msg.role == "user")
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::{Content, ContentBlock};
+    use serde_json::json;
+
+    fn text(role: &str, text: &str) -> Message {
+        Message::new(role.to_string(), Content::from_text(text), None)
+    }
+
+    fn tool_call(id: &str) -> Message {
+        Message::new(
+            "assistant".to_string(),
+            Content(vec![ContentBlock::ToolUse {
+                id: id.to_string(),
+                name: "lookup".to_string(),
+                input: json!({}),
+            }]),
+            None,
+        )
+    }
+
+    fn tool_result(id: &str) -> Message {
+        Message::new(
+            "tool".to_string(),
+            Content(vec![ContentBlock::ToolResult {
+                tool_use_id: id.to_string(),
+                name: "lookup".to_string(),
+                content: "found".to_string(),
+                is_error: false,
+            }]),
+            None,
+        )
+    }
+
+    // Two plain turns, then a question still in the middle of a tool loop
+    fn history() -> Vec<Message> {
+        vec![
+            text("user", "one"),
+            text("assistant", "reply one"),
+            text("user", "two"),
+            text("assistant", "reply two"),
+            text("user", "three"),
+            tool_call("call_1"),
+            tool_result("call_1"),
+            tool_call("call_2"),
+            tool_result("call_2"),
+        ]
+    }
+
+    fn roles(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|msg| msg.role.as_str()).collect()
+    }
+
+    #[test]
+    fn last_n_keeps_the_latest_turn_whole_during_a_tool_loop() {
+        let history = history();
+        for count in [1, 2, 4] {
+            assert_eq!(
+                roles(last_n(&history, count)),
+                ["user", "assistant", "tool", "assistant", "tool"],
+                "count {}",
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn last_n_cuts_only_where_a_user_turn_starts() {
+        let history = history();
+        // Seven messages reach back into the second turn, which is left out whole
+        assert_eq!(last_n(&history, 7).len(), 7);
+        assert_eq!(last_n(&history, 6).len(), 5);
+        assert_eq!(last_n(&history, 9).len(), 9);
+        assert_eq!(last_n(&history, 100).len(), 9);
+        assert_eq!(last_n(&history, 8)[0].role, "user");
+    }
+
+    #[test]
+    fn within_budget_never_splits_a_call_from_its_result() {
+        let history = history();
+        let latest_turn = estimate_history(&history[4..]);
+
+        // A budget too small for anything still sends the latest turn
+        assert_eq!(within_budget(&history, 1).len(), 5);
+        assert_eq!(within_budget(&history, latest_turn).len(), 5);
+        // Room for part of the previous turn isn't enough to include it
+        let previous_reply = estimate_history(&history[3..4]);
+        assert_eq!(
+            within_budget(&history, latest_turn + previous_reply).len(),
+            5
+        );
+        assert_eq!(
+            within_budget(&history, estimate_history(&history[2..])).len(),
+            7
+        );
+        assert_eq!(within_budget(&history, u64::MAX).len(), 9);
+    }
+
+    #[test]
+    fn histories_without_a_user_turn_send_nothing() {
+        let history = vec![text("assistant", "hello"), tool_call("call_1")];
+        assert!(last_n(&history, 10).is_empty());
+        assert!(within_budget(&history, u64::MAX).is_empty());
+        assert!(from_first_turn(&history).is_empty());
+    }
+
+    #[test]
+    fn from_first_turn_drops_leading_replies() {
+        let mut history = history();
+        history.insert(0, text("assistant", "Welcome"));
+        assert_eq!(from_first_turn(&history).len(), 9);
+        assert_eq!(from_first_turn(&history)[0].role, "user");
+    }
+}
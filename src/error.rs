@@ -0,0 +1,144 @@
+use serde_json::{json, Value};
+use std::fmt;
+
+// Failures reported back to clients, as `error` frames over the WebSocket or
+// JSON error bodies over HTTP
+#[derive(Debug, Clone)]
+pub enum ChatError {
+    // The message store was unreachable or gave back something unusable
+    Store(String),
+    // The model provider failed or gave back something unusable
+    Provider(String),
+    // The provider asked us to slow down
+    RateLimited {
+        message: String,
+        retry_after: Option<u64>,
+    },
+    // The provider is temporarily over capacity
+    Overloaded {
+        message: String,
+        retry_after: Option<u64>,
+    },
+    // The provider failed on its side with a 5xx status
+    ServerError {
+        status: u16,
+        message: String,
+        retry_after: Option<u64>,
+    },
+    // The request itself was malformed
+    Validation(String),
+    // A chat or message that doesn't exist
+    NotFound(String),
+    // A stored message whose contents no longer match its id
+    Integrity(String),
+    // A WebSocket command type the actor doesn't know
+    UnknownCommand(String),
+    // A client and the actor share no protocol version
+    UnsupportedProtocol(String),
+}
+
+impl ChatError {
+    pub fn store(e: impl fmt::Display) -> Self {
+        Self::Store(e.to_string())
+    }
+
+    pub fn provider(e: impl fmt::Display) -> Self {
+        Self::Provider(e.to_string())
+    }
+
+    pub fn validation(e: impl fmt::Display) -> Self {
+        Self::Validation(e.to_string())
+    }
+
+    // Failures that may succeed if the same request is tried again
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. } | Self::Overloaded { .. } | Self::ServerError { .. }
+        )
+    }
+
+    // Seconds the client should wait before trying again, when known
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::RateLimited { retry_after, .. }
+            | Self::Overloaded { retry_after, .. }
+            | Self::ServerError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    // Suggests a wait on a retryable error that didn't come with one
+    pub fn or_retry_after(mut self, seconds: u64) -> Self {
+        if let Self::RateLimited { retry_after, .. }
+        | Self::Overloaded { retry_after, .. }
+        | Self::ServerError { retry_after, .. } = &mut self
+        {
+            retry_after.get_or_insert(seconds);
+        }
+        self
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Store(_) => "store_error",
+            Self::Provider(_) | Self::ServerError { .. } => "provider_error",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Overloaded { .. } => "overloaded",
+            Self::Validation(_) => "validation_error",
+            Self::NotFound(_) => "not_found",
+            Self::Integrity(_) => "integrity_error",
+            Self::UnknownCommand(_) => "unknown_command",
+            Self::UnsupportedProtocol(_) => "unsupported_protocol",
+        }
+    }
+
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Self::Store(_) | Self::Integrity(_) => 500,
+            Self::Provider(_) | Self::ServerError { .. } => 502,
+            Self::RateLimited { .. } => 429,
+            Self::Overloaded { .. } => 503,
+            Self::Validation(_) | Self::UnknownCommand(_) | Self::UnsupportedProtocol(_) => 400,
+            Self::NotFound(_) => 404,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut body = json!({
+            "code": self.code(),
+            "message": self.to_string(),
+        });
+        if let Some(retry_after) = self.retry_after() {
+            body["retry_after"] = json!(retry_after);
+        }
+        body
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Store(message) => write!(f, "Message store error: {}", message),
+            Self::Provider(message) => write!(f, "Model provider error: {}", message),
+            Self::RateLimited { message, .. } => write!(f, "Rate limited: {}", message),
+            Self::Overloaded { message, .. } => {
+                write!(f, "Model provider overloaded: {}", message)
+            }
+            Self::ServerError {
+                status, message, ..
+            } => {
+                write!(f, "Model provider error: HTTP {}: {}", status, message)
+            }
+            Self::Validation(message) => write!(f, "Invalid request: {}", message),
+            Self::NotFound(message) => write!(f, "Not found: {}", message),
+            Self::Integrity(message) => write!(f, "Message failed verification: {}", message),
+            Self::UnknownCommand(command) => write!(f, "Unknown command: {}", command),
+            Self::UnsupportedProtocol(message) => {
+                write!(f, "Unsupported protocol version: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
@@ -0,0 +1,203 @@
+use crate::content::ContentBlock;
+use crate::error::ChatError;
+use crate::metadata::format_timestamp;
+use crate::{Chat, Message};
+use serde_json::json;
+
+// Version of the JSON export layout, checked on import
+pub const EXPORT_VERSION: u32 = 1;
+
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    // The whole tree, with everything needed to import it again
+    Json,
+    // The current branch, one message per line
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Result<Self, ChatError> {
+        match format {
+            "markdown" | "md" => Ok(Self::Markdown),
+            "html" => Ok(Self::Html),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            other => Err(ChatError::validation(format!(
+                "Unknown export format {}; expected markdown, html, json or jsonl",
+                other
+            ))),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Markdown => "text/markdown; charset=utf-8",
+            Self::Html => "text/html; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Html => "html",
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+        }
+    }
+
+    // Whether the export covers every branch rather than just the current one
+    pub fn whole_tree(&self) -> bool {
+        matches!(self, Self::Json)
+    }
+
+    pub fn render(
+        &self,
+        chat_id: &str,
+        chat: &Chat,
+        messages: &[Message],
+    ) -> Result<String, ChatError> {
+        match self {
+            Self::Markdown => Ok(markdown(chat, messages)),
+            Self::Html => Ok(html(chat, messages)),
+            Self::Json => {
+                let export = json!({
+                    "format": "single-chat",
+                    "version": EXPORT_VERSION,
+                    "chat": {
+                        "id": chat_id,
+                        "title": chat.title,
+                        "head": chat.head,
+                        "leaves": chat.leaves,
+                        "settings": chat.settings,
+                    },
+                    "messages": messages,
+                });
+                serde_json::to_string_pretty(&export).map_err(ChatError::store)
+            }
+            Self::Jsonl => messages
+                .iter()
+                .map(|msg| serde_json::to_string(msg).map_err(ChatError::store))
+                .map(|line| line.map(|line| line + "\n"))
+                .collect(),
+        }
+    }
+}
+
+fn role_label(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// When a message was written and the model and token counts behind it, e.g.
+// "2026-01-05 14:03:12 UTC · claude-3-5-sonnet-20241022 · 12 in / 40 out tokens"
+fn details(msg: &Message) -> Option<String> {
+    let mut parts = Vec::new();
+    if let Some(created_at) = msg.created_at() {
+        parts.push(format_timestamp(created_at));
+    }
+    if let Some(model) = msg.model() {
+        parts.push(model.to_string());
+    }
+    if let Some(usage) = msg.usage() {
+        parts.push(format!(
+            "{} in / {} out tokens",
+            usage.input_tokens, usage.output_tokens
+        ));
+    }
+    (!parts.is_empty()).then(|| parts.join(" · "))
+}
+
+// The message's blocks as readable text; thinking is left out
+fn body(msg: &Message) -> String {
+    let parts: Vec<String> = msg
+        .content
+        .0
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } => Some(text.trim_end().to_string()),
+            ContentBlock::Image { .. } => Some("[image]".to_string()),
+            ContentBlock::Document { title, .. } => Some(match title {
+                Some(title) => format!("[document: {}]", title),
+                None => "[document]".to_string(),
+            }),
+            ContentBlock::Thinking { .. } => None,
+            ContentBlock::ToolUse { name, input, .. } => {
+                Some(format!("Called {} with {}", name, input))
+            }
+            ContentBlock::ToolResult {
+                name,
+                content,
+                is_error,
+                ..
+            } => {
+                let outcome = if *is_error { "failed" } else { "returned" };
+                Some(format!("{} {}:\n{}", name, outcome, content.trim_end()))
+            }
+        })
+        .filter(|part| !part.is_empty())
+        .collect();
+    parts.join("\n\n")
+}
+
+fn markdown(chat: &Chat, messages: &[Message]) -> String {
+    let mut out = format!("# {}\n", chat.title);
+    for msg in messages {
+        out.push_str(&format!("\n### {}", role_label(&msg.role)));
+        if let Some(details) = details(msg) {
+            out.push_str(&format!(" ({})", details));
+        }
+        out.push_str(&format!("\n\n{}\n", body(msg)));
+    }
+    out
+}
+
+fn html(chat: &Chat, messages: &[Message]) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>\n\
+         body {{ font-family: system-ui, sans-serif; max-width: 800px; margin: 2rem auto; color: #1f2937; }}\n\
+         .message {{ padding: 0.75rem 1rem; border-radius: 0.75rem; margin-bottom: 0.75rem; background: #e5e7eb; }}\n\
+         .message.user {{ background: #4361ee; color: white; }}\n\
+         .role {{ font-weight: 600; font-size: 0.875rem; }}\n\
+         .details {{ font-weight: normal; opacity: 0.7; }}\n\
+         .content {{ white-space: pre-wrap; margin-top: 0.25rem; }}\n\
+         </style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        escape_html(&chat.title),
+        escape_html(&chat.title)
+    );
+    for msg in messages {
+        let details = details(msg)
+            .map(|details| format!(" <span class=\"details\">{}</span>", escape_html(&details)))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "<div class=\"message {}\">\n<div class=\"role\">{}{}</div>\n<div class=\"content\">{}</div>\n</div>\n",
+            escape_html(&msg.role),
+            escape_html(&role_label(&msg.role)),
+            details,
+            escape_html(&body(msg))
+        ));
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,401 @@
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::content::{Content, ContentBlock, MediaSource};
+use crate::error::ChatError;
+use crate::export::EXPORT_VERSION;
+use crate::settings::ChatSettings;
+use crate::Message;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// A transcript turned into messages that still need saving. Ids here are
+// only the transcript's own and get replaced once the messages are stored.
+pub struct ImportedChat {
+    pub title: Option<String>,
+    pub settings: Option<ChatSettings>,
+    // System prompt found in a role/content transcript
+    pub system: Option<String>,
+    pub messages: Vec<Message>,
+    pub head: Option<String>,
+    pub leaves: Vec<String>,
+}
+
+// Accepts this actor's JSON export, or a role/content array as sent to the
+// Anthropic Messages API or the OpenAI chat-completions API (bare, or as the
+// `messages` of a request body)
+pub fn parse(transcript: &Value) -> Result<ImportedChat, ChatError> {
+    if transcript["format"].as_str() == Some("single-chat") {
+        return parse_export(transcript);
+    }
+    let (messages, system) = match transcript {
+        Value::Array(messages) => (messages, None),
+        Value::Object(body) => match body.get("messages").and_then(Value::as_array) {
+            Some(messages) => (messages, body.get("system").and_then(text)),
+            None => return Err(unrecognized()),
+        },
+        _ => return Err(unrecognized()),
+    };
+    parse_role_content(messages, system)
+}
+
+fn unrecognized() -> ChatError {
+    ChatError::validation(
+        "Unrecognized transcript; expected a single-chat export or an array of role/content messages",
+    )
+}
+
+fn parse_export(export: &Value) -> Result<ImportedChat, ChatError> {
+    let version = export["version"].as_u64().unwrap_or(0);
+    if version == 0 || version > EXPORT_VERSION as u64 {
+        return Err(ChatError::validation(format!(
+            "Unsupported export version {}",
+            version
+        )));
+    }
+
+    let chat = &export["chat"];
+    let messages: Vec<Message> =
+        serde_json::from_value(export["messages"].clone()).map_err(ChatError::validation)?;
+    if messages.iter().any(|msg| msg.id.is_none()) {
+        return Err(ChatError::validation("Exported message without an id"));
+    }
+    let settings = match chat.get("settings") {
+        Some(settings) if !settings.is_null() => {
+            Some(serde_json::from_value(settings.clone()).map_err(ChatError::validation)?)
+        }
+        _ => None,
+    };
+
+    Ok(ImportedChat {
+        title: chat["title"].as_str().map(str::to_string),
+        settings,
+        system: None,
+        messages,
+        head: chat["head"].as_str().map(str::to_string),
+        leaves: serde_json::from_value(chat["leaves"].clone()).unwrap_or_default(),
+    })
+}
+
+fn parse_role_content(
+    entries: &[Value],
+    system: Option<String>,
+) -> Result<ImportedChat, ChatError> {
+    let mut system_parts: Vec<String> = system.into_iter().collect();
+    let mut messages: Vec<Message> = Vec::new();
+    // Tools called so far by call id, to name the results that answer them
+    let mut tool_names: HashMap<String, String> = HashMap::new();
+
+    for (index, entry) in entries.iter().enumerate() {
+        let role = entry["role"].as_str().unwrap_or_default();
+        let mut blocks = match role {
+            // OpenAI puts the system prompt in the array itself
+            "system" | "developer" => {
+                system_parts.extend(text(&entry["content"]));
+                continue;
+            }
+            "user" | "assistant" => blocks(&entry["content"]),
+            // OpenAI's answer to a tool call
+            "tool" => vec![ContentBlock::ToolResult {
+                tool_use_id: entry["tool_call_id"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                name: entry["name"].as_str().unwrap_or_default().to_string(),
+                content: text(&entry["content"]).unwrap_or_default(),
+                is_error: false,
+            }],
+            other => {
+                log(&format!(
+                    "Skipping transcript entry {} with role {:?}",
+                    index, other
+                ));
+                continue;
+            }
+        };
+        // OpenAI keeps an assistant's tool calls beside its content
+        for call in entry["tool_calls"].as_array().into_iter().flatten() {
+            blocks.push(openai_tool_call(call));
+        }
+
+        // Anthropic answers tool calls on the user's turn; they are kept as
+        // `tool` messages, one per result, as this actor writes them
+        let (results, rest): (Vec<ContentBlock>, Vec<ContentBlock>) = blocks
+            .into_iter()
+            .partition(|block| matches!(block, ContentBlock::ToolResult { .. }));
+        let turns = results
+            .into_iter()
+            .map(|result| ("tool", vec![result]))
+            .chain((!rest.is_empty()).then_some((role, rest)));
+        for (role, mut blocks) in turns {
+            for block in &mut blocks {
+                match block {
+                    ContentBlock::ToolUse { id, name, .. } => {
+                        tool_names.insert(id.clone(), name.clone());
+                    }
+                    ContentBlock::ToolResult {
+                        tool_use_id, name, ..
+                    } if name.is_empty() => {
+                        *name = tool_names
+                            .get(tool_use_id)
+                            .cloned()
+                            .unwrap_or_else(|| "tool".to_string());
+                    }
+                    _ => {}
+                }
+            }
+            let parent = messages.last().and_then(|msg| msg.id.clone());
+            messages.push(
+                Message::new(role.to_string(), Content(blocks), parent)
+                    .with_id(messages.len().to_string()),
+            );
+        }
+    }
+
+    if !messages
+        .iter()
+        .any(|msg| msg.role == "user" || msg.role == "assistant")
+    {
+        return Err(ChatError::validation(
+            "Transcript has no user or assistant messages",
+        ));
+    }
+    let head = messages.last().and_then(|msg| msg.id.clone());
+    Ok(ImportedChat {
+        title: None,
+        settings: None,
+        system: (!system_parts.is_empty()).then(|| system_parts.join("\n\n")),
+        messages,
+        leaves: head.iter().cloned().collect(),
+        head,
+    })
+}
+
+// Blocks of a `content` field: a string, Anthropic blocks or OpenAI parts.
+// Blocks with nothing this actor can keep, such as redacted thinking, are left out.
+fn blocks(content: &Value) -> Vec<ContentBlock> {
+    match content {
+        Value::String(text) if !text.is_empty() => vec![ContentBlock::Text { text: text.clone() }],
+        Value::Array(blocks) => blocks.iter().filter_map(block).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn block(block: &Value) -> Option<ContentBlock> {
+    match block["type"].as_str()? {
+        "text" if block["text"].as_str()?.is_empty() => None,
+        // Anthropic tool results carry no name and may hold blocks of their own
+        "tool_result" => Some(ContentBlock::ToolResult {
+            tool_use_id: block["tool_use_id"].as_str()?.to_string(),
+            name: String::new(),
+            content: text(&block["content"]).unwrap_or_default(),
+            is_error: block["is_error"].as_bool().unwrap_or(false),
+        }),
+        "image_url" => {
+            let url = block["image_url"]["url"].as_str()?;
+            Some(ContentBlock::Image {
+                source: media_source(url),
+            })
+        }
+        // Anthropic blocks share this actor's shapes
+        _ => serde_json::from_value(block.clone()).ok(),
+    }
+}
+
+// An image URL, with data URLs unpacked into base64 sources
+fn media_source(url: &str) -> MediaSource {
+    url.strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+        .map_or_else(
+            || MediaSource::Url {
+                url: url.to_string(),
+            },
+            |(media_type, data)| MediaSource::Base64 {
+                media_type: media_type.to_string(),
+                data: data.to_string(),
+            },
+        )
+}
+
+fn openai_tool_call(call: &Value) -> ContentBlock {
+    // Arguments arrive as JSON text
+    let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+    ContentBlock::ToolUse {
+        id: call["id"].as_str().unwrap_or_default().to_string(),
+        name: call["function"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        input: serde_json::from_str(arguments)
+            .unwrap_or_else(|_| Value::String(arguments.to_string())),
+    }
+}
+
+// Text of a `content` field: a string, or the text parts of a block array
+fn text(content: &Value) -> Option<String> {
+    match content {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(blocks) => {
+            let parts: Vec<&str> = blocks
+                .iter()
+                .filter(|block| block["type"].as_str() == Some("text"))
+                .filter_map(|block| block["text"].as_str())
+                .collect();
+            (!parts.is_empty()).then(|| parts.join("\n\n"))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::ExportFormat;
+    use crate::Chat;
+    use serde_json::json;
+
+    fn roles(chat: &ImportedChat) -> Vec<&str> {
+        chat.messages.iter().map(|msg| msg.role.as_str()).collect()
+    }
+
+    #[test]
+    fn anthropic_requests_keep_images_and_split_out_tool_results() {
+        let transcript = json!({
+            "model": "claude-3-5-sonnet-20241022",
+            "system": "Be brief",
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "text", "text": "What's this?"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0K"}}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Let me check."},
+                    {"type": "tool_use", "id": "toolu_1", "name": "lookup", "input": {"q": "logo"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": [{"type": "text", "text": "A logo"}]},
+                    {"type": "text", "text": "Well?"}
+                ]},
+                {"role": "assistant", "content": "It's a logo."}
+            ]
+        });
+
+        let chat = parse(&transcript).unwrap();
+        assert_eq!(chat.system.as_deref(), Some("Be brief"));
+        assert_eq!(
+            roles(&chat),
+            ["user", "assistant", "tool", "user", "assistant"]
+        );
+        assert!(matches!(
+            &chat.messages[0].content.0[1],
+            ContentBlock::Image { source: MediaSource::Base64 { media_type, .. } }
+                if media_type == "image/png"
+        ));
+        assert!(matches!(
+            &chat.messages[1].content.0[1],
+            ContentBlock::ToolUse { id, name, input }
+                if id == "toolu_1" && name == "lookup" && input["q"] == "logo"
+        ));
+        assert!(matches!(
+            &chat.messages[2].content.0[..],
+            [ContentBlock::ToolResult { tool_use_id, name, content, is_error: false }]
+                if tool_use_id == "toolu_1" && name == "lookup" && content == "A logo"
+        ));
+
+        // Messages form one chain ending at the head
+        for (index, msg) in chat.messages.iter().enumerate().skip(1) {
+            assert_eq!(msg.parent, chat.messages[index - 1].id);
+        }
+        assert_eq!(chat.head.as_deref(), Some("4"));
+        assert_eq!(chat.leaves, ["4"]);
+    }
+
+    #[test]
+    fn openai_messages_keep_tool_calls_and_data_url_images() {
+        let transcript = json!([
+            {"role": "system", "content": "Be brief"},
+            {"role": "user", "content": [
+                {"type": "text", "text": "Look"},
+                {"type": "image_url", "image_url": {"url": "data:image/jpeg;base64,/9j/4AAQ"}},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}}
+            ]},
+            {"role": "assistant", "content": null, "tool_calls": [
+                {"id": "call_1", "type": "function", "function": {"name": "lookup", "arguments": "{\"q\":\"cat\"}"}}
+            ]},
+            {"role": "tool", "tool_call_id": "call_1", "content": "A cat"},
+            {"role": "assistant", "content": "It's a cat."}
+        ]);
+
+        let chat = parse(&transcript).unwrap();
+        assert_eq!(chat.system.as_deref(), Some("Be brief"));
+        assert_eq!(roles(&chat), ["user", "assistant", "tool", "assistant"]);
+        assert!(matches!(
+            &chat.messages[0].content.0[1],
+            ContentBlock::Image { source: MediaSource::Base64 { media_type, data } }
+                if media_type == "image/jpeg" && data == "/9j/4AAQ"
+        ));
+        assert!(matches!(
+            &chat.messages[0].content.0[2],
+            ContentBlock::Image { source: MediaSource::Url { url } }
+                if url == "https://example.com/cat.png"
+        ));
+        assert!(matches!(
+            &chat.messages[1].content.0[..],
+            [ContentBlock::ToolUse { id, name, input }]
+                if id == "call_1" && name == "lookup" && input["q"] == "cat"
+        ));
+        assert!(matches!(
+            &chat.messages[2].content.0[..],
+            [ContentBlock::ToolResult { tool_use_id, name, content, .. }]
+                if tool_use_id == "call_1" && name == "lookup" && content == "A cat"
+        ));
+    }
+
+    #[test]
+    fn transcripts_without_a_conversation_are_rejected() {
+        assert!(parse(&json!([{"role": "system", "content": "Be brief"}])).is_err());
+        assert!(parse(&json!({"title": "Not a transcript"})).is_err());
+        assert!(parse(&json!({"format": "single-chat", "version": 99})).is_err());
+    }
+
+    #[test]
+    fn json_exports_import_with_their_tree_intact() {
+        let message = |role: &str, text: &str, parent: Option<&Message>| {
+            let msg = Message::new(
+                role.to_string(),
+                Content::from_text(text),
+                parent.and_then(|parent| parent.id.clone()),
+            );
+            let id = msg.content_id().unwrap();
+            msg.with_id(id)
+        };
+        let root = message("user", "Hi", None);
+        let first = message("assistant", "Hello", Some(&root));
+        let second = message("assistant", "Hey there", Some(&root));
+        let messages = vec![root, first.clone(), second.clone()];
+
+        let mut chat = Chat::new(
+            "Greetings".to_string(),
+            second.id.clone(),
+            ChatSettings::default(),
+        );
+        chat.leaves = vec![first.id.clone().unwrap(), second.id.clone().unwrap()];
+
+        let export = ExportFormat::Json.render("3", &chat, &messages).unwrap();
+        let imported = parse(&serde_json::from_str(&export).unwrap()).unwrap();
+
+        assert_eq!(imported.title.as_deref(), Some("Greetings"));
+        assert_eq!(imported.head, second.id);
+        assert_eq!(imported.leaves, chat.leaves);
+        assert_eq!(
+            imported.settings.map(|settings| settings.model),
+            Some(chat.settings.model)
+        );
+        assert_eq!(imported.messages.len(), messages.len());
+        for (imported, original) in imported.messages.iter().zip(&messages) {
+            assert_eq!(imported.id, original.id);
+            assert_eq!(imported.parent, original.parent);
+            assert_eq!(imported.content.text(), original.content.text());
+            // Still stored under the same ids
+            assert_eq!(imported.content_id().ok(), original.id);
+        }
+    }
+}
@@ -1,5 +1,26 @@
+mod attachments;
+#[allow(warnings)]
 mod bindings;
+mod cache;
+mod clients;
+mod content;
+mod context;
+mod error;
+mod export;
+mod import;
+mod metadata;
+mod protocol;
+mod providers;
+mod retry;
+mod search;
+mod secrets;
+mod settings;
+mod store;
+mod tools;
+mod usage;
+mod verify;
 
+use attachments::{Attachment, AttachmentInfo};
 use bindings::exports::ntwk::theater::actor::Guest as ActorGuest;
 use bindings::exports::ntwk::theater::http_server::Guest as HttpGuest;
 use bindings::exports::ntwk::theater::http_server::{
@@ -10,191 +31,1241 @@ use bindings::exports::ntwk::theater::websocket_server::Guest as WebSocketGuest;
 use bindings::exports::ntwk::theater::websocket_server::{
     MessageType, WebsocketMessage, WebsocketResponse,
 };
-use bindings::ntwk::theater::filesystem::{path_exists, read_file};
-use bindings::ntwk::theater::http_client::{send_http, HttpRequest};
-use bindings::ntwk::theater::message_server_host::{request, send};
+use bindings::ntwk::theater::filesystem::read_file;
 use bindings::ntwk::theater::runtime::log;
 use bindings::ntwk::theater::types::Json;
+use content::{Content, ContentBlock, MediaSource};
+use context::{ContextInfo, ContextStrategy};
+use error::ChatError;
+use export::ExportFormat;
+use import::ImportedChat;
+use metadata::{MessageMetadata, Origin};
+use protocol::{ClientCommand, Envelope, ServerEvent, PROTOCOL_VERSIONS};
+use providers::{
+    default_providers, AnthropicProvider, Completion, LlmProvider, MockProvider, OpenAiProvider,
+    ProviderConfig, ProviderTable,
+};
+use retry::{with_retry, Attempt};
+use search::{SearchHit, SearchIndex};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashMap;
+use settings::ChatSettings;
+use std::collections::{HashMap, HashSet};
+use store::StoreConfig;
+use tools::{ToolChoice, ToolRegistry};
+use usage::{default_prices, PriceTable, Usage, UsageTotals};
+use verify::{Issue, MessageProblem, VerifyReport};
 
 // Message struct changes - making id optional
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Message {
     role: String,
-    content: String,
+    content: Content,
     parent: Option<String>,
     id: Option<String>, // Now optional
+    // Generation attempts behind an assistant message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attempts: Vec<Attempt>,
+    // How the history sent for an assistant message was trimmed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    context: Option<ContextInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<MessageMetadata>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct AnthropicMessage {
-    role: String,
-    content: String,
-}
+type ChatId = String;
+
+// Chat used by commands and routes that don't name one
+const DEFAULT_CHAT_ID: &str = "default";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Chat {
+    title: String,
     head: Option<String>,
+    // Tips of every branch in the message tree, including the current head
+    #[serde(default)]
+    leaves: Vec<String>,
+    #[serde(default)]
+    settings: ChatSettings,
+    #[serde(default)]
+    usage: UsageTotals,
+    // Rolling summaries, keyed by the last message each one covers
+    #[serde(default)]
+    summaries: HashMap<String, String>,
+}
+
+impl Chat {
+    fn new(title: String, head: Option<String>, settings: ChatSettings) -> Self {
+        Self {
+            title,
+            leaves: head.iter().cloned().collect(),
+            head,
+            settings,
+            usage: UsageTotals::default(),
+            summaries: HashMap::new(),
+        }
+    }
+
+    // A new message replaces its parent as a leaf; siblings keep their own branches
+    fn track_leaf(&mut self, id: &str, parent: Option<&str>) {
+        if let Some(parent) = parent {
+            self.leaves.retain(|leaf| leaf != parent);
+        }
+        if !self.leaves.iter().any(|leaf| leaf == id) {
+            self.leaves.push(id.to_string());
+        }
+    }
 }
 
 impl Message {
-    fn new(role: String, content: String, parent: Option<String>) -> Self {
+    fn new(role: String, content: Content, parent: Option<String>) -> Self {
         Self {
             role,
             content,
             parent,
             id: None, // No ID until stored
+            attempts: Vec::new(),
+            context: None,
+            metadata: None,
         }
     }
 
+    fn with_attempts(mut self, attempts: Vec<Attempt>) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    fn with_metadata(mut self, metadata: MessageMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    fn with_context(mut self, context: ContextInfo) -> Self {
+        self.context = Some(context);
+        self
+    }
+
     // Helper to create a message with ID (for after storage)
     fn with_id(mut self, id: String) -> Self {
         self.id = Some(id);
         self
     }
+
+    // Model that produced an assistant message
+    fn model(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.model.as_deref())
+    }
+
+    fn usage(&self) -> Option<&Usage> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.usage.as_ref())
+    }
+
+    fn created_at(&self) -> Option<u64> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.created_at)
+    }
+
+    // Everything but the id, serialized with sorted keys so the same message
+    // always gives the same bytes
+    fn canonical_bytes(&self) -> Result<Vec<u8>, ChatError> {
+        let mut value = serde_json::to_value(self).map_err(ChatError::store)?;
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("id");
+        }
+        serde_json::to_vec(&value).map_err(ChatError::store)
+    }
+
+    // The id a message is stored under: the SHA-1 of its canonical bytes
+    fn content_id(&self) -> Result<String, ChatError> {
+        Ok(store::sha1_hex(&self.canonical_bytes()?))
+    }
+
+    // Parses stored bytes and checks them against the id they were stored under
+    fn decode(id: &str, bytes: &[u8]) -> Result<Result<Message, MessageProblem>, ChatError> {
+        let mut msg: Message = match serde_json::from_slice::<Message>(bytes) {
+            Ok(msg) => msg,
+            Err(e) => {
+                return Ok(Err(MessageProblem::Unparseable {
+                    error: e.to_string(),
+                }))
+            }
+        };
+
+        // Messages stored before ids were computed here are keyed by the hash of
+        // their raw bytes instead
+        let computed = msg.content_id()?;
+        if computed != id && store::sha1_hex(bytes) != id {
+            return Ok(Err(MessageProblem::HashMismatch {
+                computed,
+                parent: msg.parent,
+            }));
+        }
+
+        msg.id = Some(id.to_string());
+        Ok(Ok(msg))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct State {
-    chat: Chat,
-    api_key: String,
-    connected_clients: HashMap<String, bool>,
-    store_id: String,
+    chats: HashMap<ChatId, Chat>,
+    next_chat_id: u64,
+    // Settings given to newly created chats
+    default_settings: ChatSettings,
+    // USD per million tokens, by model
+    prices: PriceTable,
+    // Backends chats may choose between, by name
+    #[serde(default = "default_providers")]
+    providers: ProviderTable,
+    // Usage across every chat, including deleted ones
+    usage: UsageTotals,
+    // File the Anthropic key is read from; the key itself is kept out of state
+    api_key_file: String,
+    // Kept here so client ids stay unique across restarts; the clients
+    // themselves are tracked in instance memory
+    #[serde(default)]
+    next_client_id: u64,
+    store: StoreConfig,
+    // Tools offered to every chat that allows them
+    #[serde(default)]
+    tools: ToolRegistry,
     websocket_port: u16,
 }
 
-// Import the Request/Action types - we'll need to define these since we can't import from store actor
-#[derive(Serialize, Deserialize, Debug)]
-struct Request {
-    _type: String,
-    data: Action,
-}
+impl State {
+    // Serializes the state for the runtime, which persists it and records it
+    // in the event chain. Keys never get this far: no field holds one, only
+    // the files they are read from.
+    fn to_json(&self) -> Json {
+        serde_json::to_vec(self).unwrap()
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Action {
-    Get(String),
-    Put(Vec<u8>),
-    All(()),
-}
+    fn chat(&self, chat_id: &str) -> Result<&Chat, ChatError> {
+        self.chats
+            .get(chat_id)
+            .ok_or_else(|| ChatError::NotFound(format!("chat {}", chat_id)))
+    }
 
-impl State {
-    fn save_message(&self, msg: &Message) -> Result<String, Box<dyn std::error::Error>> {
-        let req = Request {
-            _type: "request".to_string(),
-            data: Action::Put(serde_json::to_vec(&msg)?),
+    fn chat_mut(&mut self, chat_id: &str) -> Result<&mut Chat, ChatError> {
+        self.chats
+            .get_mut(chat_id)
+            .ok_or_else(|| ChatError::NotFound(format!("chat {}", chat_id)))
+    }
+
+    fn create_chat(&mut self, title: String) -> ChatId {
+        self.next_chat_id += 1;
+        let chat_id = format!("chat-{}", self.next_chat_id);
+        let chat = Chat::new(title, None, self.default_settings.clone());
+        self.chats.insert(chat_id.clone(), chat);
+        chat_id
+    }
+
+    // Saves a parsed transcript as a new chat, parents before children, and
+    // returns the chat's id along with how many messages were stored
+    fn import_chat(
+        &mut self,
+        title: Option<String>,
+        imported: ImportedChat,
+    ) -> Result<(ChatId, usize), ChatError> {
+        // A transcript's provider profile may mean something else on this
+        // actor, so imported chats use the default one
+        let settings = imported
+            .settings
+            .map(|settings| ChatSettings {
+                provider: self.default_settings.provider.clone(),
+                ..settings
+            })
+            .map(|settings| settings.validate(&self.providers).map(|()| settings))
+            .transpose()?;
+
+        let mut pending: HashMap<String, Message> = imported
+            .messages
+            .into_iter()
+            .filter_map(|msg| msg.id.clone().map(|id| (id, msg)))
+            .collect();
+        let total = pending.len();
+        // Transcript ids to the ids the messages were stored under
+        let mut stored: HashMap<String, String> = HashMap::new();
+        let mut saved = Vec::new();
+
+        while !pending.is_empty() {
+            let ready: Vec<String> = pending
+                .iter()
+                .filter(|(_, msg)| {
+                    msg.parent
+                        .as_ref()
+                        .is_none_or(|parent| stored.contains_key(parent))
+                })
+                .map(|(id, _)| id.clone())
+                .collect();
+            if ready.is_empty() {
+                return Err(ChatError::validation(
+                    "Transcript has messages whose parent is missing or loops",
+                ));
+            }
+            for (id, mut msg) in ready
+                .into_iter()
+                .filter_map(|id| pending.remove(&id).map(|msg| (id, msg)))
+            {
+                msg.id = None;
+                msg.parent = msg.parent.map(|parent| stored[&parent].clone());
+                let msg_id = self.save_message(&msg)?;
+                saved.push(msg.with_id(msg_id.clone()));
+                stored.insert(id, msg_id);
+            }
+        }
+
+        let resolve = |id: &String| {
+            stored
+                .get(id)
+                .cloned()
+                .ok_or_else(|| ChatError::validation(format!("Transcript head {} not found", id)))
         };
+        let head = imported.head.as_ref().map(resolve).transpose()?;
+        let leaves = imported
+            .leaves
+            .iter()
+            .map(resolve)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let title = title
+            .or(imported.title)
+            .unwrap_or_else(|| "Imported chat".to_string());
+        let chat_id = self.create_chat(title);
+        let chat = self.chat_mut(&chat_id)?;
+        if let Some(settings) = settings {
+            chat.settings = settings;
+        }
+        if imported.system.is_some() {
+            chat.settings.system = imported.system;
+        }
+        chat.head = head;
+        chat.leaves = leaves;
+        if let Some(head) = chat.head.clone() {
+            chat.track_leaf(&head, None);
+        }
+        self.index_messages(&chat_id, &saved);
+        self.save_search_index();
 
-        let request_bytes = serde_json::to_vec(&req)?;
-        let response_bytes = request(&self.store_id, &request_bytes)?;
+        log(&format!("Imported {} messages into {}", total, chat_id));
+        Ok((chat_id, total))
+    }
 
-        let response: Value = serde_json::from_slice(&response_bytes)?;
-        if response["status"].as_str() == Some("ok") {
-            response["key"]
-                .as_str()
-                .map(|s| s.to_string())
-                .ok_or("No key in response".into())
-        } else {
-            Err("Failed to save message".into())
+    fn rename_chat(&mut self, chat_id: &str, title: String) -> Result<(), ChatError> {
+        self.chat_mut(chat_id)?.title = title;
+        Ok(())
+    }
+
+    // Only forgets the chat; its messages stay in the store
+    // The default chat stays, since every command without a `chat_id` uses it
+    fn delete_chat(&mut self, chat_id: &str) -> Result<(), ChatError> {
+        if chat_id == DEFAULT_CHAT_ID {
+            return Err(ChatError::validation("The default chat can't be deleted"));
         }
+        self.chats
+            .remove(chat_id)
+            .map(|_| ())
+            .ok_or_else(|| ChatError::NotFound(format!("chat {}", chat_id)))
     }
 
-    fn load_message(&self, id: &str) -> Result<Message, Box<dyn std::error::Error>> {
-        let req = Request {
-            _type: "request".to_string(),
-            data: Action::Get(id.to_string()),
-        };
+    fn list_chats(&self) -> Vec<Value> {
+        let mut chat_ids: Vec<&ChatId> = self.chats.keys().collect();
+        chat_ids.sort();
+        chat_ids
+            .into_iter()
+            .map(|chat_id| {
+                let chat = &self.chats[chat_id];
+                json!({
+                    "id": chat_id,
+                    "title": chat.title,
+                    "head": chat.head,
+                })
+            })
+            .collect()
+    }
 
-        let request_bytes = serde_json::to_vec(&req)?;
-        let response_bytes = request(&self.store_id, &request_bytes)?;
+    fn update_settings(
+        &mut self,
+        chat_id: &str,
+        changes: &Value,
+    ) -> Result<ChatSettings, ChatError> {
+        let settings = self
+            .chat(chat_id)?
+            .settings
+            .merge(changes, &self.providers)?;
+        self.chat_mut(chat_id)?.settings = settings.clone();
+        Ok(settings)
+    }
 
-        let response: Value = serde_json::from_slice(&response_bytes)?;
-        if response["status"].as_str() == Some("ok") {
-            if let Some(value) = response.get("value") {
-                // The value should be an array of bytes that we can directly deserialize
-                let bytes = value
-                    .as_array()
-                    .ok_or("Expected byte array")?
-                    .iter()
-                    .map(|v| v.as_u64().unwrap_or(0) as u8)
-                    .collect::<Vec<u8>>();
-                let mut msg: Message = serde_json::from_slice(&bytes)?;
-                msg.id = Some(id.to_string());
-                return Ok(msg);
+    fn connect_client(&mut self) -> String {
+        self.next_client_id += 1;
+        let client_id = format!("client-{}", self.next_client_id);
+        clients::connect(&client_id);
+        client_id
+    }
+
+    fn broadcast_chat_list(&self) {
+        clients::broadcast(None, None, &chat_list_event(self, None).to_frame(None));
+    }
+
+    fn save_message(&self, msg: &Message) -> Result<String, ChatError> {
+        let bytes = msg.canonical_bytes()?;
+        let id = store::sha1_hex(&bytes);
+        self.store.backend().put(&id, &bytes)?;
+        cache::remember_message(&msg.clone().with_id(id.clone()));
+        Ok(id)
+    }
+
+    fn load_message(&self, id: &str) -> Result<Message, ChatError> {
+        if let Some(msg) = cache::message(id) {
+            return Ok(msg);
+        }
+        let msg = self.inspect_message(id)?.map_err(|problem| match problem {
+            MessageProblem::Missing => ChatError::NotFound(format!("message {}", id)),
+            MessageProblem::Unparseable { error } => ChatError::Store(error),
+            MessageProblem::HashMismatch { computed, .. } => {
+                ChatError::Integrity(format!("message {} hashes to {}", id, computed))
             }
+            MessageProblem::Cycle => ChatError::Integrity(format!("message {} loops", id)),
+        })?;
+        cache::remember_message(&msg);
+        Ok(msg)
+    }
+
+    // Uploads share the message store, under the hash of their record
+    fn save_attachment(
+        &self,
+        bytes: &[u8],
+        media_type: Option<&str>,
+    ) -> Result<AttachmentInfo, ChatError> {
+        let attachment = Attachment::new(bytes, media_type)?;
+        let (id, record) = attachment.encode()?;
+        self.store.backend().put(&id, &record)?;
+        log(&format!(
+            "Stored attachment {} ({}, {} bytes)",
+            id,
+            attachment.media_type,
+            bytes.len()
+        ));
+        Ok(AttachmentInfo {
+            attachment_id: id,
+            media_type: attachment.media_type,
+            size: bytes.len(),
+        })
+    }
+
+    fn load_attachment(&self, id: &str) -> Result<Attachment, ChatError> {
+        let record = self
+            .store
+            .backend()
+            .get(id)?
+            .ok_or_else(|| ChatError::NotFound(format!("attachment {}", id)))?;
+        if store::sha1_hex(&record) != id {
+            return Err(ChatError::Integrity(format!(
+                "attachment {} no longer matches its id",
+                id
+            )));
         }
-        Err("Failed to load message".into())
+        serde_json::from_slice(&record)
+            .map_err(|_| ChatError::NotFound(format!("attachment {}", id)))
     }
 
-    fn get_message_history(&self) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
-        let mut messages = Vec::new();
-        let mut current_id = self.chat.head.clone();
+    // Makes sure every upload `content` refers to exists and suits its block
+    fn check_attachments(&self, content: &Content) -> Result<(), ChatError> {
+        for block in &content.0 {
+            let (attachment_id, wants_image) = match block {
+                ContentBlock::Image {
+                    source: MediaSource::Attachment { attachment_id },
+                } => (attachment_id, true),
+                ContentBlock::Document {
+                    source: MediaSource::Attachment { attachment_id },
+                    ..
+                } => (attachment_id, false),
+                _ => continue,
+            };
+            let attachment = self.load_attachment(attachment_id)?;
+            if attachment.is_image() != wants_image {
+                return Err(ChatError::validation(format!(
+                    "Attachment {} is {}, which doesn't belong in a{} block",
+                    attachment_id,
+                    attachment.media_type,
+                    if wants_image { "n image" } else { " document" }
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Swaps references to uploads for their contents, as providers need them
+    fn resolve_attachments(&self, messages: &mut [Message]) -> Result<(), ChatError> {
+        for msg in messages {
+            for block in &mut msg.content.0 {
+                let source = match block {
+                    ContentBlock::Image { source } | ContentBlock::Document { source, .. } => {
+                        source
+                    }
+                    _ => continue,
+                };
+                let MediaSource::Attachment { attachment_id } = source else {
+                    continue;
+                };
+                let attachment = self.load_attachment(attachment_id)?;
+                *source = if attachment.is_text() {
+                    // Providers only take text documents as plain text
+                    MediaSource::Text {
+                        media_type: "text/plain".to_string(),
+                        data: String::from_utf8(attachment.bytes()?).map_err(ChatError::store)?,
+                    }
+                } else {
+                    MediaSource::Base64 {
+                        media_type: attachment.media_type,
+                        data: attachment.data,
+                    }
+                };
+            }
+        }
+        Ok(())
+    }
 
+    // Loads a message, telling the ways stored data can be broken (the inner
+    // error) apart from failing to reach the store at all (the outer one)
+    fn inspect_message(&self, id: &str) -> Result<Result<Message, MessageProblem>, ChatError> {
+        match self.store.backend().get(id)? {
+            Some(bytes) => Message::decode(id, &bytes),
+            None => Ok(Err(MessageProblem::Missing)),
+        }
+    }
+
+    // Loads a message on a branch being walked, asking the store for its
+    // ancestors in the same round trip when it isn't cached
+    fn load_ancestor(&self, id: &str) -> Result<Message, ChatError> {
+        if cache::message(id).is_none() {
+            if let Some(entries) = self.store.backend().get_ancestry(id)? {
+                for (key, bytes) in entries {
+                    // Broken ones are left for `load_message` to report
+                    if let Ok(msg) = Message::decode(&key, &bytes)? {
+                        cache::remember_message(&msg);
+                    }
+                }
+            }
+        }
+        self.load_message(id)
+    }
+
+    // Fetches whichever of `ids` aren't cached yet in one batch
+    fn prefetch(&self, ids: &[String]) -> Result<(), ChatError> {
+        let missing: Vec<String> = ids
+            .iter()
+            .filter(|id| cache::message(id).is_none())
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let values = self.store.backend().get_many(&missing)?;
+        for (id, bytes) in missing.iter().zip(values) {
+            let Some(bytes) = bytes else {
+                continue;
+            };
+            if let Ok(msg) = Message::decode(id, &bytes)? {
+                cache::remember_message(&msg);
+            }
+        }
+        Ok(())
+    }
+
+    // Walks the current branch reporting every broken message; with `repair`,
+    // moves the head back to the newest message whose ancestry is intact
+    fn verify_chat(&mut self, chat_id: &str, repair: bool) -> Result<VerifyReport, ChatError> {
+        let head = self.chat(chat_id)?.head.clone();
+        let mut seen = HashSet::new();
+        // Head first, each with whether it is sound on its own
+        let mut chain: Vec<(String, bool)> = Vec::new();
+        let mut issues = Vec::new();
+        let mut complete = true;
+
+        let mut current_id = head.clone();
         while let Some(id) = current_id {
-            let msg = self.load_message(&id)?;
-            messages.push(msg.clone());
+            if !seen.insert(id.clone()) {
+                issues.push(Issue {
+                    message_id: id,
+                    problem: MessageProblem::Cycle,
+                });
+                complete = false;
+                break;
+            }
+            current_id = match self.inspect_message(&id)? {
+                Ok(msg) => {
+                    chain.push((id, true));
+                    msg.parent
+                }
+                Err(problem) => {
+                    let parent = match &problem {
+                        MessageProblem::HashMismatch { parent, .. } => parent.clone(),
+                        _ => {
+                            complete = false;
+                            None
+                        }
+                    };
+                    chain.push((id.clone(), false));
+                    issues.push(Issue {
+                        message_id: id,
+                        problem,
+                    });
+                    parent
+                }
+            };
+        }
+
+        // Only a branch that reached its root can have an intact ancestor
+        let last_good = if complete {
+            let sound_from = chain
+                .iter()
+                .rposition(|(_, ok)| !ok)
+                .map_or(0, |broken| broken + 1);
+            chain.get(sound_from).map(|(id, _)| id.clone())
+        } else {
+            None
+        };
+
+        let ok = issues.is_empty();
+        // Without an intact ancestor there is nothing to re-root at, and clearing
+        // the head would throw the whole chat away over one lost message
+        let repaired = repair && !ok && last_good.is_some();
+        if repair && !ok && !repaired {
+            log(&format!(
+                "Chat {} has no intact ancestor; leaving the head at {:?}",
+                chat_id, head
+            ));
+        }
+        if repaired {
+            let chat = self.chat_mut(chat_id)?;
+            if let Some(old_head) = &head {
+                chat.leaves.retain(|leaf| leaf != old_head);
+            }
+            if let Some(new_head) = &last_good {
+                chat.track_leaf(new_head, None);
+            }
+            chat.head = last_good.clone();
+            log(&format!(
+                "Chat {} re-rooted from {:?} to {:?}",
+                chat_id, head, last_good
+            ));
+        }
+
+        Ok(VerifyReport {
+            chat_id: chat_id.to_string(),
+            head,
+            ok,
+            messages_checked: chain.len(),
+            issues,
+            last_good,
+            repaired,
+        })
+    }
+
+    fn get_message_history(&self, chat_id: &str) -> Result<Vec<Message>, ChatError> {
+        self.get_history_from(self.chat(chat_id)?.head.clone())
+    }
+
+    fn get_history_from(&self, head: Option<String>) -> Result<Vec<Message>, ChatError> {
+        let Some(head) = head else {
+            return Ok(Vec::new());
+        };
+
+        // Walk back only as far as the nearest branch already materialized,
+        // which after a normal turn is the previous head
+        let mut messages = Vec::new();
+        let mut newer = Vec::new();
+        let mut current_id = Some(head.clone());
+        while let Some(id) = current_id {
+            if let Some(history) = cache::history(&id) {
+                messages = history;
+                break;
+            }
+            let msg = self.load_ancestor(&id)?;
             current_id = msg.parent.clone();
+            newer.push(msg);
         }
 
-        messages.reverse(); // Oldest first
+        messages.extend(newer.into_iter().rev()); // Oldest first
+        cache::remember_history(&head, &messages);
         Ok(messages)
     }
 
-    fn update_head(&mut self, message_id: String) -> Result<(), Box<dyn std::error::Error>> {
-        self.chat.head = Some(message_id);
-        Ok(())
-    }
+    // Every message reachable from any branch, each message listed once
+    fn get_tree(&self, chat_id: &str) -> Result<Vec<Message>, ChatError> {
+        let chat = self.chat(chat_id)?;
+        let mut seen = HashSet::new();
+        let mut messages = Vec::new();
 
-    fn generate_response(
-        &self,
-        messages: Vec<Message>,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        let anthropic_messages: Vec<AnthropicMessage> = messages
+        let tips: Vec<String> = chat
+            .leaves
             .iter()
-            .map(|msg| AnthropicMessage {
-                role: msg.role.clone(),
-                content: msg.content.clone(),
-            })
+            .chain(chat.head.iter())
+            .cloned()
             .collect();
+        self.prefetch(&tips)?;
+        for leaf in tips {
+            let mut current_id = Some(leaf);
+            while let Some(id) = current_id {
+                if !seen.insert(id.clone()) {
+                    break;
+                }
+                let msg = self.load_ancestor(&id)?;
+                current_id = msg.parent.clone();
+                messages.push(msg);
+            }
+        }
 
-        let request = HttpRequest {
-            method: "POST".to_string(),
-            uri: "https://api.anthropic.com/v1/messages".to_string(),
-            headers: vec![
-                ("Content-Type".to_string(), "application/json".to_string()),
-                ("x-api-key".to_string(), self.api_key.clone()),
-                ("anthropic-version".to_string(), "2023-06-01".to_string()),
-            ],
-            body: Some(
-                serde_json::to_vec(&json!({
-                    "model": "claude-3-5-sonnet-20241022",
-                    "max_tokens": 1024,
-                    "messages": anthropic_messages,
-                }))
-                .unwrap(),
-            ),
+        messages.reverse(); // Roots first
+        Ok(messages)
+    }
+
+    // Loads a message that has to be on one of the chat's branches, so ids from
+    // other chats can't be grafted onto this one
+    fn load_chat_message(&self, chat_id: &str, message_id: &str) -> Result<Message, ChatError> {
+        let chat = self.chat(chat_id)?;
+        let mut seen = HashSet::new();
+        for tip in chat.leaves.iter().chain(chat.head.iter()) {
+            let mut current_id = Some(tip.clone());
+            while let Some(id) = current_id {
+                if !seen.insert(id.clone()) {
+                    break;
+                }
+                let msg = self.load_ancestor(&id)?;
+                if id == message_id {
+                    return Ok(msg);
+                }
+                current_id = msg.parent;
+            }
+        }
+        Err(ChatError::NotFound(format!(
+            "message {} on chat {}",
+            message_id, chat_id
+        )))
+    }
+
+    fn update_head(&mut self, chat_id: &str, message_id: String) -> Result<(), ChatError> {
+        self.chat_mut(chat_id)?.head = Some(message_id);
+        Ok(())
+    }
+
+    // Stores a message, records it in the tree and moves the head to it
+    fn add_message(&mut self, chat_id: &str, msg: Message) -> Result<Message, ChatError> {
+        self.chat(chat_id)?;
+        let msg_id = self.save_message(&msg)?;
+        self.chat_mut(chat_id)?
+            .track_leaf(&msg_id, msg.parent.as_deref());
+        self.update_head(chat_id, msg_id.clone())?;
+        let msg = msg.with_id(msg_id);
+        self.index_messages(chat_id, std::slice::from_ref(&msg));
+        Ok(msg)
+    }
+
+    // Generates an assistant reply to the branch ending at `parent` and appends
+    // it. Tools the model calls are run and their output appended as `tool`
+    // messages for it to carry on from. After the chat's `max_tool_rounds` the
+    // model is asked once more without being allowed to call tools, so the
+    // reply always ends with an assistant message. Returns every message added,
+    // the final reply last.
+    fn respond(
+        &mut self,
+        chat_id: &str,
+        parent: Option<String>,
+        origin: &Origin,
+    ) -> Result<Vec<Message>, ChatError> {
+        let max_rounds = self.chat(chat_id)?.settings.max_tool_rounds;
+
+        let mut added = Vec::new();
+        let mut parent = parent;
+        let mut rounds = 0;
+        loop {
+            let last_round = rounds >= max_rounds;
+            let tool_choice = if last_round {
+                ToolChoice::None
+            } else {
+                ToolChoice::Auto
+            };
+            let tools = self.tools.clone();
+            let ai_msg = self.reply(chat_id, &tools, tool_choice, parent, origin)?;
+            let tool_calls = ai_msg.content.tool_calls();
+            parent = ai_msg.id.clone();
+            added.push(ai_msg);
+            if tool_calls.is_empty() || last_round {
+                break;
+            }
+
+            for call in tool_calls {
+                log(&format!("Running tool {} ({})", call.name, call.id));
+                let (content, is_error) = tools::run(&tools, &call);
+                let result = ContentBlock::ToolResult {
+                    tool_use_id: call.id,
+                    name: call.name,
+                    content,
+                    is_error,
+                };
+                let tool_msg = Message::new("tool".to_string(), Content(vec![result]), parent);
+                let tool_msg = self.add_message(chat_id, tool_msg)?;
+                parent = tool_msg.id.clone();
+                added.push(tool_msg);
+            }
+            rounds += 1;
+        }
+        Ok(added)
+    }
+
+    // One model turn: generates a reply to the branch ending at `parent` and appends it
+    fn reply(
+        &mut self,
+        chat_id: &str,
+        tools: &ToolRegistry,
+        tool_choice: ToolChoice,
+        parent: Option<String>,
+        origin: &Origin,
+    ) -> Result<Message, ChatError> {
+        let settings = self.chat(chat_id)?.settings.clone();
+        let history = self.get_history_from(parent.clone())?;
+        let (settings, mut messages, context) = self.build_context(chat_id, settings, history)?;
+        self.resolve_attachments(&mut messages)?;
+        let (completion, attempts) =
+            self.generate_response(&settings, tools, tool_choice, messages)?;
+
+        // A fallback may have answered instead of the chat's own model
+        let model = attempts
+            .last()
+            .map_or(settings.model.clone(), |attempt| attempt.model.clone());
+        let usage = completion.usage;
+        let metadata = MessageMetadata {
+            created_at: completion.created_at,
+            model: Some(model.clone()),
+            stop_reason: completion.stop_reason,
+            latency_ms: origin
+                .sent_at
+                .zip(completion.created_at)
+                .and_then(|(sent_at, created_at)| created_at.checked_sub(sent_at)),
+            usage: Some(usage.clone()),
+            client_id: origin.client_id.clone(),
         };
+        let ai_msg = Message::new("assistant".to_string(), completion.content, parent)
+            .with_attempts(attempts)
+            .with_context(context)
+            .with_metadata(metadata);
+        let ai_msg = self.add_message(chat_id, ai_msg)?;
+
+        self.record_usage(chat_id, &model, &usage)?;
+        Ok(ai_msg)
+    }
+
+    fn record_usage(&mut self, chat_id: &str, model: &str, usage: &Usage) -> Result<(), ChatError> {
+        let price = self.prices.get(model).cloned();
+        self.usage.record(usage, price.as_ref());
+        self.chat_mut(chat_id)?.usage.record(usage, price.as_ref());
+        Ok(())
+    }
+
+    // Applies the chat's context strategy to `history`, returning the settings
+    // and messages to send along with a record of what was left out
+    fn build_context(
+        &mut self,
+        chat_id: &str,
+        mut settings: ChatSettings,
+        history: Vec<Message>,
+    ) -> Result<(ChatSettings, Vec<Message>, ContextInfo), ChatError> {
+        let mut summary_id = None;
+        let selected = match settings.context {
+            ContextStrategy::Full => &history[..],
+            ContextStrategy::LastN { count } => context::last_n(&history, count),
+            ContextStrategy::TokenBudget { max_tokens } => {
+                context::within_budget(&history, max_tokens)
+            }
+            ContextStrategy::Summary {
+                max_tokens,
+                keep_recent,
+            } => {
+                let (summary, recent) =
+                    self.summarize(chat_id, &settings, &history, max_tokens, keep_recent)?;
+                if let Some(summary) = summary {
+                    let prompt = format!(
+                        "Summary of the earlier conversation:\n{}",
+                        summary.content.text()
+                    );
+                    settings.system = Some(match settings.system.take() {
+                        Some(system) => format!("{}\n\n{}", system, prompt),
+                        None => prompt,
+                    });
+                    summary_id = summary.id;
+                }
+                recent
+            }
+        };
+        let messages = context::from_first_turn(selected).to_vec();
+
+        let context = ContextInfo {
+            strategy: settings.context.name().to_string(),
+            messages_sent: messages.len(),
+            messages_omitted: history.len() - messages.len(),
+            estimated_tokens: context::estimate_history(&messages)
+                + settings
+                    .system
+                    .as_deref()
+                    .map_or(0, context::estimate_tokens),
+            summary_id,
+        };
+        Ok((settings, messages, context))
+    }
+
+    // Finds the latest summary on this branch and, when the messages after it
+    // no longer fit in `max_tokens`, folds all but the last `keep_recent` of
+    // them into a new one. Returns the summary in effect and the messages it
+    // doesn't cover.
+    fn summarize<'a>(
+        &mut self,
+        chat_id: &str,
+        settings: &ChatSettings,
+        history: &'a [Message],
+        max_tokens: u64,
+        keep_recent: usize,
+    ) -> Result<(Option<Message>, &'a [Message]), ChatError> {
+        let chat = self.chat(chat_id)?;
+        let latest = history.iter().enumerate().rev().find_map(|(i, msg)| {
+            let summary_id = chat.summaries.get(msg.id.as_ref()?)?;
+            Some((i + 1, summary_id.clone()))
+        });
+        let (start, mut summary) = match latest {
+            Some((start, summary_id)) => (start, Some(self.load_message(&summary_id)?)),
+            None => (0, None),
+        };
+
+        let recent = &history[start..];
+        let summary_tokens = summary.as_ref().map_or(0, |summary| {
+            context::estimate_tokens(&summary.content.text())
+        });
+        if summary_tokens + context::estimate_history(recent) <= max_tokens {
+            return Ok((summary, recent));
+        }
+
+        // Folding stops at a turn boundary, like the other strategies' cuts
+        let kept = context::last_n(recent, keep_recent);
+        let (folded, recent) = recent.split_at(recent.len() - kept.len());
+        if folded.is_empty() {
+            return Ok((summary, recent));
+        }
+        let mut transcript = String::new();
+        if let Some(summary) = &summary {
+            transcript.push_str(&format!("Earlier summary:\n{}\n\n", summary.content.text()));
+        }
+        for msg in folded {
+            transcript.push_str(&format!("{}: {}\n\n", msg.role, msg.content.full_text()));
+        }
+        let prompt = Message::new(
+            "user".to_string(),
+            Content::from_text(format!(
+                "Summarise the following conversation so it can stand in for it as context. \
+                 Keep names, decisions, open questions and anything the user asked to remember.\n\n{}",
+                transcript
+            )),
+            None,
+        );
+        let summary_settings = ChatSettings {
+            system: None,
+            stop_sequences: Vec::new(),
+            ..settings.clone()
+        };
+        let (completion, attempts) = self.generate_response(
+            &summary_settings,
+            &ToolRegistry::new(),
+            ToolChoice::None,
+            vec![prompt],
+        )?;
+
+        // Stored off to the side of the branch, hanging from the last message it covers
+        let covered_id = folded
+            .last()
+            .and_then(|msg| msg.id.clone())
+            .ok_or_else(|| ChatError::store("Summarised message has no id"))?;
+        let model = attempts
+            .last()
+            .map_or(settings.model.clone(), |attempt| attempt.model.clone());
+        // Only the text is kept; the summary stands in for conversation, not reasoning
+        let new_summary = Message::new(
+            "summary".to_string(),
+            Content::from_text(completion.content.text()),
+            Some(covered_id.clone()),
+        )
+        .with_attempts(attempts)
+        .with_metadata(MessageMetadata {
+            created_at: completion.created_at,
+            model: Some(model.clone()),
+            stop_reason: completion.stop_reason,
+            usage: Some(completion.usage.clone()),
+            ..MessageMetadata::default()
+        });
+        let summary_id = self.save_message(&new_summary)?;
+        summary = Some(new_summary.with_id(summary_id.clone()));
+
+        self.record_usage(chat_id, &model, &completion.usage)?;
+        self.chat_mut(chat_id)?
+            .summaries
+            .insert(covered_id, summary_id);
+
+        Ok((summary, recent))
+    }
 
-        let http_response = send_http(&request);
+    // Loads the search index, rebuilding it from every chat's tree when
+    // nothing usable has been persisted yet. A chat whose tree can't be read is
+    // left out rather than making every later save try the rebuild again.
+    fn ensure_search_index(&self) {
+        if search::is_loaded() || search::load() {
+            return;
+        }
+        let mut index = SearchIndex::default();
+        for chat_id in self.chats.keys() {
+            match self.get_tree(chat_id) {
+                Ok(messages) => {
+                    for msg in messages {
+                        if let Some(id) = &msg.id {
+                            index.add(id, chat_id, &msg.content.full_text());
+                        }
+                    }
+                }
+                Err(e) => search::log_failure(&format!("rebuild of {}", chat_id), &e),
+            }
+        }
+        search::install(index);
+    }
+
+    // Adds messages to the index in memory; `save_search_index` writes it out
+    fn index_messages(&self, chat_id: &str, messages: &[Message]) {
+        self.ensure_search_index();
+        for msg in messages {
+            if let Some(id) = &msg.id {
+                search::add(id, chat_id, &msg.content.full_text());
+            }
+        }
+    }
+
+    // Search is a convenience, so failing to persist the index never fails the
+    // command that changed it
+    fn save_search_index(&self) {
+        if let Err(e) = search::save() {
+            search::log_failure("save", &e);
+        }
+    }
+
+    // Messages in live chats containing every word of `query`, best first
+    fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchHit>, ChatError> {
+        let terms: Vec<String> = search::tokenize(query)
+            .into_iter()
+            .map(|token| token.text)
+            .collect();
+        if terms.is_empty() {
+            return Err(ChatError::validation("Search query has no words"));
+        }
+        self.ensure_search_index();
+        self.save_search_index();
 
-        if let Some(body) = http_response.body {
-            if let Ok(response_data) = serde_json::from_slice::<Value>(&body) {
-                if let Some(text) = response_data["content"][0]["text"].as_str() {
-                    return Ok(text.to_string());
+        let mut hits = Vec::new();
+        for (message_id, chats) in search::find(&terms) {
+            let live: Vec<&ChatId> = chats
+                .iter()
+                .filter(|chat_id| self.chats.contains_key(*chat_id))
+                .collect();
+            if live.is_empty() {
+                continue;
+            }
+            let msg = match self.load_message(&message_id) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    search::log_failure("lookup", &e);
+                    continue;
                 }
+            };
+            let (snippet, highlights, score) = search::snippet(&msg.content.full_text(), &terms);
+            for chat_id in live {
+                hits.push(SearchHit {
+                    chat_id: chat_id.clone(),
+                    message_id: message_id.clone(),
+                    role: msg.role.clone(),
+                    snippet: snippet.clone(),
+                    highlights: highlights.clone(),
+                    score,
+                });
             }
         }
+        hits.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then_with(|| a.chat_id.cmp(&b.chat_id))
+                .then_with(|| a.message_id.cmp(&b.message_id))
+        });
+        hits.truncate(limit);
+        Ok(hits)
+    }
+
+    fn usage_report(&self) -> Value {
+        let chats: serde_json::Map<String, Value> = self
+            .chats
+            .iter()
+            .map(|(chat_id, chat)| {
+                (
+                    chat_id.clone(),
+                    json!({
+                        "title": chat.title,
+                        "usage": chat.usage,
+                    }),
+                )
+            })
+            .collect();
+        json!({
+            "total": self.usage,
+            "chats": chats,
+        })
+    }
+
+    fn send_message(
+        &mut self,
+        chat_id: &str,
+        content: Content,
+        origin: &Origin,
+    ) -> Result<(Message, Vec<Message>), ChatError> {
+        content.validate_user()?;
+        self.check_attachments(&content)?;
+        let user_msg = Message::new(
+            "user".to_string(),
+            content,
+            self.chat(chat_id)?.head.clone(),
+        )
+        .with_metadata(origin.metadata());
+        let user_msg = self.add_message(chat_id, user_msg)?;
+        let replies = self.respond(chat_id, user_msg.id.clone(), origin);
+        // Once per turn, and even when the reply failed after the prompt was stored
+        self.save_search_index();
+        Ok((user_msg, replies?))
+    }
+
+    // Answers the same prompt again as a sibling of an existing assistant message
+    fn regenerate_message(
+        &mut self,
+        chat_id: &str,
+        message_id: &str,
+        origin: &Origin,
+    ) -> Result<Vec<Message>, ChatError> {
+        let original = self.load_chat_message(chat_id, message_id)?;
+        if original.role != "assistant" {
+            return Err(ChatError::validation(
+                "Only assistant messages can be regenerated",
+            ));
+        }
+        let parent = original
+            .parent
+            .ok_or_else(|| ChatError::validation("Message has no parent"))?;
+
+        let replies = self.respond(chat_id, Some(parent), origin);
+        self.save_search_index();
+        replies?;
+        self.get_message_history(chat_id)
+    }
+
+    // Replaces a user message with a sibling carrying new content, then answers it
+    fn edit_message(
+        &mut self,
+        chat_id: &str,
+        message_id: &str,
+        content: Content,
+        origin: &Origin,
+    ) -> Result<Vec<Message>, ChatError> {
+        let original = self.load_chat_message(chat_id, message_id)?;
+        if original.role != "user" {
+            return Err(ChatError::validation("Only user messages can be edited"));
+        }
+        content.validate_user()?;
+        self.check_attachments(&content)?;
+
+        let edited = Message::new("user".to_string(), content, original.parent)
+            .with_metadata(origin.metadata());
+        let edited = self.add_message(chat_id, edited)?;
+        let replies = self.respond(chat_id, edited.id, origin);
+        self.save_search_index();
+        replies?;
+        self.get_message_history(chat_id)
+    }
+
+    fn provider(&self, name: &str) -> Result<Box<dyn LlmProvider>, ChatError> {
+        let config = self
+            .providers
+            .get(name)
+            .ok_or_else(|| ChatError::Validation(format!("Unknown provider profile {}", name)))?;
+        Ok(match config {
+            ProviderConfig::Anthropic => {
+                Box::new(AnthropicProvider::new(secrets::load(&self.api_key_file)?))
+            }
+            ProviderConfig::OpenAi {
+                base_url,
+                api_key_file,
+            } => {
+                let api_key = match api_key_file {
+                    Some(path) => Some(secrets::load(path)?),
+                    None => None,
+                };
+                Box::new(OpenAiProvider::new(base_url.clone(), api_key))
+            }
+            ProviderConfig::Mock => Box::new(MockProvider),
+        })
+    }
 
-        Err("Failed to generate response".into())
+    fn generate_response(
+        &self,
+        settings: &ChatSettings,
+        tools: &ToolRegistry,
+        tool_choice: ToolChoice,
+        messages: Vec<Message>,
+    ) -> Result<(Completion, Vec<Attempt>), ChatError> {
+        let provider = self.provider(&settings.provider)?;
+        // The runtime gives actors no timer, so backoffs can't be waited out
+        let result = with_retry(settings, &mut |_| false, |settings| {
+            provider.generate(settings, tools, tool_choice, &messages)
+        });
+        if let Err(e) = &result {
+            log(&format!("Generation failed after retries: {}", e));
+        }
+        result
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct InitData {
-    store_id: String,
+    // Shorthand for an `actor` store
+    #[serde(default)]
+    store_id: Option<String>,
+    #[serde(default)]
+    store: Option<StoreConfig>,
     head: Option<String>,
     websocket_port: u16,
+    #[serde(default)]
+    default_settings: ChatSettings,
+    // Added to, and overriding, the built-in price table
+    #[serde(default)]
+    prices: PriceTable,
+    // Added to, and overriding, the built-in `anthropic` and `mock` profiles
+    #[serde(default)]
+    providers: ProviderTable,
+    #[serde(default = "default_api_key_file")]
+    api_key_file: String,
+    // Key given directly instead of through `api_key_file`; held in memory only
+    #[serde(default, skip_serializing)]
+    api_key: Option<String>,
+    #[serde(default)]
+    tools: ToolRegistry,
+}
+
+fn default_api_key_file() -> String {
+    "api-key.txt".to_string()
 }
 
 struct Component;
@@ -203,44 +1274,60 @@ impl ActorGuest for Component {
     fn init(data: Option<Vec<u8>>) -> Vec<u8> {
         log("Initializing single chat actor");
         let data = data.unwrap();
-        log(&format!("Data: {:?}", data));
 
         let init_data: InitData = serde_json::from_slice(&data).unwrap();
 
-        log(&format!("Store actor id: {}", init_data.store_id));
+        // An explicit store wins over `store_id`; with neither, messages go to files
+        let store = match (init_data.store, init_data.store_id) {
+            (Some(store), _) => store,
+            (None, Some(store_id)) => StoreConfig::Actor { store_id },
+            (None, None) => StoreConfig::default(),
+        };
+        log(&format!("Message store: {:?}", store));
         log(&format!("Head: {:?}", init_data.head));
         log(&format!("Websocket port: {}", init_data.websocket_port));
 
-        // Read API key
-        log("Reading API key");
-        let res = read_file("api-key.txt");
-        if res.is_err() {
-            log("Failed to read API key");
-            return vec![];
-        }
-        let api_key = res.unwrap();
-        log("API key read");
-        let api_key = String::from_utf8(api_key).unwrap().trim().to_string();
-        log("API key loaded");
-
-        // Load or create chat
-        let chat = Chat {
-            head: init_data.head,
-        };
+        // The API key is loaded on first use so it never enters the state
+        if let Some(api_key) = init_data.api_key {
+            secrets::remember(&init_data.api_key_file, api_key);
+            log("API key taken from init data");
+        }
+
+        // The configured head seeds the default chat
+        let mut chats = HashMap::new();
+        chats.insert(
+            DEFAULT_CHAT_ID.to_string(),
+            Chat::new(
+                "Default".to_string(),
+                init_data.head,
+                init_data.default_settings.clone(),
+            ),
+        );
 
         log("Chat loaded");
 
+        let mut prices = default_prices();
+        prices.extend(init_data.prices);
+        let mut providers = default_providers();
+        providers.extend(init_data.providers);
+
         let initial_state = State {
-            chat,
-            api_key,
-            connected_clients: HashMap::new(),
-            store_id: init_data.store_id,
+            chats,
+            next_chat_id: 0,
+            default_settings: init_data.default_settings,
+            prices,
+            providers,
+            usage: UsageTotals::default(),
+            api_key_file: init_data.api_key_file,
+            next_client_id: 0,
+            store,
+            tools: init_data.tools,
             websocket_port: init_data.websocket_port,
         };
 
         log("State initialized");
 
-        serde_json::to_vec(&initial_state).unwrap()
+        initial_state.to_json()
     }
 }
 
@@ -296,35 +1383,120 @@ impl HttpGuest for Component {
                 )
             }
 
-            ("GET", "/api/messages") => {
+            ("GET", "/api/usage") => {
                 let current_state: State = serde_json::from_slice(&state).unwrap();
-                match current_state.get_message_history() {
-                    Ok(messages) => (
-                        HttpResponse {
+                let mut body = current_state.usage_report();
+                body["status"] = json!("success");
+                (json_response(200, body), state)
+            }
+
+            ("GET", uri) if uri.split('?').next() == Some("/api/search") => {
+                let current_state: State = serde_json::from_slice(&state).unwrap();
+                let query = query_params(uri.split_once('?').map_or("", |(_, query)| query));
+                let response = search_response(&current_state, &query)
+                    .unwrap_or_else(|e| chat_error_response(&e));
+                (response, state)
+            }
+
+            ("POST", "/api/attachments") => {
+                let current_state: State = serde_json::from_slice(&state).unwrap();
+                let media_type = req
+                    .headers
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+                    .map(|(_, value)| value.as_str());
+                let response = current_state
+                    .save_attachment(req.body.as_deref().unwrap_or_default(), media_type)
+                    .map(|attachment| {
+                        json_response(
+                            201,
+                            json!({
+                                "status": "success",
+                                "attachment": attachment
+                            }),
+                        )
+                    })
+                    .unwrap_or_else(|e| chat_error_response(&e));
+                (response, state)
+            }
+
+            ("GET", uri) if uri.starts_with("/api/attachments/") => {
+                let current_state: State = serde_json::from_slice(&state).unwrap();
+                let id = uri.trim_start_matches("/api/attachments/");
+                let response = current_state
+                    .load_attachment(id)
+                    .and_then(|attachment| {
+                        // Served as a download the browser mustn't sniff, so an
+                        // upload never runs as a page on the actor's origin;
+                        // `<img>` still shows images
+                        Ok(HttpResponse {
                             status: 200,
-                            headers: vec![(
-                                "Content-Type".to_string(),
-                                "application/json".to_string(),
-                            )],
-                            body: Some(
-                                serde_json::to_vec(&json!({
-                                    "status": "success",
-                                    "messages": messages
-                                }))
-                                .unwrap(),
-                            ),
-                        },
-                        state,
-                    ),
-                    Err(_) => (
-                        HttpResponse {
-                            status: 500,
-                            headers: vec![],
-                            body: Some(b"Failed to load messages".to_vec()),
-                        },
-                        state,
-                    ),
-                }
+                            headers: vec![
+                                ("Content-Type".to_string(), attachment.media_type.clone()),
+                                (
+                                    "Content-Disposition".to_string(),
+                                    format!("attachment; filename=\"{}\"", id),
+                                ),
+                                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+                            ],
+                            body: Some(attachment.bytes()?),
+                        })
+                    })
+                    .unwrap_or_else(|e| chat_error_response(&e));
+                (response, state)
+            }
+
+            (method, uri) if uri.starts_with("/api/tools") => {
+                let mut current_state: State = serde_json::from_slice(&state).unwrap();
+                let body: Value = req
+                    .body
+                    .as_deref()
+                    .and_then(|body| serde_json::from_slice(body).ok())
+                    .unwrap_or(Value::Null);
+                let response = handle_tools_api(
+                    &mut current_state,
+                    method,
+                    &path_segments(uri.trim_start_matches("/api/tools")),
+                    &body,
+                );
+                (
+                    response.unwrap_or_else(|e| chat_error_response(&e)),
+                    current_state.to_json(),
+                )
+            }
+
+            (method, uri) if uri.starts_with("/api/messages") || uri.starts_with("/api/chats") => {
+                let mut current_state: State = serde_json::from_slice(&state).unwrap();
+                let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+                let query = query_params(query);
+                let body: Value = req
+                    .body
+                    .as_deref()
+                    .and_then(|body| serde_json::from_slice(body).ok())
+                    .unwrap_or(Value::Null);
+
+                let response = if let Some(rest) = path.strip_prefix("/api/messages") {
+                    handle_messages_api(
+                        &mut current_state,
+                        method,
+                        &path_segments(rest),
+                        &query,
+                        &body,
+                    )
+                } else {
+                    handle_chats_api(
+                        &mut current_state,
+                        method,
+                        &path_segments(path.trim_start_matches("/api/chats")),
+                        &query,
+                        &body,
+                    )
+                };
+
+                (
+                    response.unwrap_or_else(|e| chat_error_response(&e)),
+                    current_state.to_json(),
+                )
             }
 
             // Default 404 response
@@ -340,114 +1512,635 @@ impl HttpGuest for Component {
     }
 }
 
+fn json_response(status: u16, body: Value) -> HttpResponse {
+    HttpResponse {
+        status,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: Some(serde_json::to_vec(&body).unwrap()),
+    }
+}
+
+fn error_response(status: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        status,
+        headers: vec![],
+        body: Some(message.as_bytes().to_vec()),
+    }
+}
+
+fn chat_error_response(error: &ChatError) -> HttpResponse {
+    log(&format!("Request failed: {}", error));
+    let mut body = error.to_json();
+    body["status"] = json!("error");
+    json_response(error.http_status(), body)
+}
+
+fn path_segments(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+// Decodes a query string such as `since=abc&limit=10`
+fn query_params(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            // `from_str_radix` alone would also take a sign, as in "%+4"
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Matches for `q` across all chats, at most `limit` of them
+fn search_response(
+    state: &State,
+    query: &HashMap<String, String>,
+) -> Result<HttpResponse, ChatError> {
+    let q = query
+        .get("q")
+        .ok_or_else(|| ChatError::validation("Missing q parameter"))?;
+    let limit = match query.get("limit") {
+        Some(limit) => limit
+            .parse()
+            .map_err(|_| ChatError::validation(format!("limit must be a number, got {}", limit)))?,
+        None => search::DEFAULT_LIMIT,
+    };
+    let results = state.search(q, limit)?;
+    Ok(json_response(
+        200,
+        json!({
+            "status": "success",
+            "query": q,
+            "results": results,
+        }),
+    ))
+}
+
+// A chat's current branch, optionally only the messages after `since` and at most `limit` of them
+fn messages_response(
+    state: &State,
+    chat_id: &str,
+    query: &HashMap<String, String>,
+) -> Result<HttpResponse, ChatError> {
+    let mut messages = state.get_message_history(chat_id)?;
+    if let Some(since) = query.get("since") {
+        let position = messages
+            .iter()
+            .position(|msg| msg.id.as_deref() == Some(since.as_str()))
+            .ok_or_else(|| ChatError::NotFound(format!("message {} on chat {}", since, chat_id)))?;
+        messages.drain(..=position);
+    }
+    let mut has_more = false;
+    if let Some(limit) = query.get("limit") {
+        let limit: usize = limit
+            .parse()
+            .map_err(|_| ChatError::validation(format!("limit must be a number, got {}", limit)))?;
+        has_more = messages.len() > limit;
+        messages.truncate(limit);
+    }
+    Ok(json_response(
+        200,
+        json!({
+            "status": "success",
+            "chat_id": chat_id,
+            "messages": messages,
+            "has_more": has_more
+        }),
+    ))
+}
+
+// Routes under `/api/messages`, with the prefix already stripped from `segments`.
+// Reads and sends go to the chat named by `chat_id` (in the query or body), or the default one.
+fn handle_messages_api(
+    state: &mut State,
+    method: &str,
+    segments: &[&str],
+    query: &HashMap<String, String>,
+    body: &Value,
+) -> Result<HttpResponse, ChatError> {
+    match (method, segments) {
+        ("GET", []) => {
+            let chat_id = query
+                .get("chat_id")
+                .map(String::as_str)
+                .unwrap_or(DEFAULT_CHAT_ID);
+            messages_response(state, chat_id, query)
+        }
+        ("GET", [message_id]) => Ok(json_response(
+            200,
+            json!({
+                "status": "success",
+                "message": state.load_message(message_id)?
+            }),
+        )),
+        ("POST", []) => {
+            let chat_id = body["chat_id"]
+                .as_str()
+                .unwrap_or(DEFAULT_CHAT_ID)
+                .to_string();
+            let content: Content = serde_json::from_value(body["content"].clone())
+                .map_err(|e| ChatError::validation(format!("invalid `content`: {}", e)))?;
+            let origin = Origin {
+                client_id: None,
+                sent_at: body["sent_at"].as_u64(),
+            };
+            let (user_msg, replies) = state.send_message(&chat_id, content, &origin)?;
+            let mut messages = vec![user_msg];
+            messages.extend(replies);
+            let update = ServerEvent::MessageUpdate {
+                chat_id: chat_id.clone(),
+                messages: messages.clone(),
+            };
+            clients::broadcast(None, Some(&chat_id), &update.to_frame(None));
+            Ok(json_response(
+                201,
+                json!({
+                    "status": "success",
+                    "chat_id": chat_id,
+                    "messages": messages
+                }),
+            ))
+        }
+        _ => Ok(error_response(404, "Not Found")),
+    }
+}
+
+// Routes under `/api/tools`, with the prefix already stripped from `segments`
+fn handle_tools_api(
+    state: &mut State,
+    method: &str,
+    segments: &[&str],
+    body: &Value,
+) -> Result<HttpResponse, ChatError> {
+    match (method, segments) {
+        ("GET", []) => Ok(json_response(
+            200,
+            json!({
+                "status": "success",
+                "tools": state.tools
+            }),
+        )),
+        ("PUT", [name]) => {
+            tools::validate_name(name)?;
+            let tool = serde_json::from_value(body.clone()).map_err(ChatError::validation)?;
+            state.tools.insert(name.to_string(), tool);
+            Ok(json_response(
+                200,
+                json!({
+                    "status": "success",
+                    "tools": state.tools
+                }),
+            ))
+        }
+        ("DELETE", [name]) => {
+            state
+                .tools
+                .remove(*name)
+                .ok_or_else(|| ChatError::NotFound(format!("tool {}", name)))?;
+            Ok(json_response(
+                200,
+                json!({
+                    "status": "success",
+                    "tools": state.tools
+                }),
+            ))
+        }
+        _ => Ok(error_response(404, "Not Found")),
+    }
+}
+
+// Routes under `/api/chats`, with the prefix already stripped from `segments`
+fn handle_chats_api(
+    state: &mut State,
+    method: &str,
+    segments: &[&str],
+    query: &HashMap<String, String>,
+    body: &Value,
+) -> Result<HttpResponse, ChatError> {
+    match (method, segments) {
+        ("GET", []) => Ok(json_response(
+            200,
+            json!({
+                "status": "success",
+                "chats": state.list_chats()
+            }),
+        )),
+        ("POST", []) => {
+            let title = body["title"].as_str().unwrap_or("New chat").to_string();
+            let chat_id = state.create_chat(title);
+            state.broadcast_chat_list();
+            Ok(json_response(
+                201,
+                json!({
+                    "status": "success",
+                    "chat_id": chat_id,
+                    "chats": state.list_chats()
+                }),
+            ))
+        }
+        ("POST", ["import"]) => {
+            let imported = import::parse(body)?;
+            let (chat_id, count) = state.import_chat(query.get("title").cloned(), imported)?;
+            state.broadcast_chat_list();
+            Ok(json_response(
+                201,
+                json!({
+                    "status": "success",
+                    "chat_id": chat_id,
+                    "head": state.chat(&chat_id)?.head,
+                    "imported": count,
+                    "chats": state.list_chats()
+                }),
+            ))
+        }
+        ("PUT", [chat_id]) => {
+            let title = required_str(body, "title")?;
+            state.rename_chat(chat_id, title.to_string())?;
+            state.broadcast_chat_list();
+            Ok(json_response(
+                200,
+                json!({
+                    "status": "success",
+                    "chats": state.list_chats()
+                }),
+            ))
+        }
+        ("DELETE", [chat_id]) => {
+            state.delete_chat(chat_id)?;
+            state.broadcast_chat_list();
+            Ok(json_response(
+                200,
+                json!({
+                    "status": "success",
+                    "chats": state.list_chats()
+                }),
+            ))
+        }
+        ("GET", [chat_id, "messages"]) => messages_response(state, chat_id, query),
+        ("GET", [chat_id, "export"]) => {
+            let format = ExportFormat::parse(
+                query
+                    .get("format")
+                    .map(String::as_str)
+                    .unwrap_or("markdown"),
+            )?;
+            let messages = if format.whole_tree() {
+                state.get_tree(chat_id)?
+            } else {
+                state.get_message_history(chat_id)?
+            };
+            let body = format.render(chat_id, state.chat(chat_id)?, &messages)?;
+            Ok(HttpResponse {
+                status: 200,
+                headers: vec![
+                    (
+                        "Content-Type".to_string(),
+                        format.content_type().to_string(),
+                    ),
+                    (
+                        "Content-Disposition".to_string(),
+                        format!(
+                            "attachment; filename=\"{}.{}\"",
+                            chat_id,
+                            format.extension()
+                        ),
+                    ),
+                ],
+                body: Some(body.into_bytes()),
+            })
+        }
+        // GET only reports; POST also re-roots a broken chat
+        (method @ ("GET" | "POST"), [chat_id, "verify"]) => {
+            let report = state.verify_chat(chat_id, method == "POST")?;
+            if report.repaired {
+                let update = ServerEvent::BranchUpdate {
+                    chat_id: chat_id.to_string(),
+                    head: report.last_good.clone(),
+                    messages: state.get_message_history(chat_id)?,
+                };
+                clients::broadcast(None, Some(chat_id), &update.to_frame(None));
+            }
+            let mut body = serde_json::to_value(&report).map_err(ChatError::store)?;
+            body["status"] = json!("success");
+            Ok(json_response(200, body))
+        }
+        ("GET", [chat_id, "settings"]) => Ok(json_response(
+            200,
+            json!({
+                "status": "success",
+                "settings": state.chat(chat_id)?.settings
+            }),
+        )),
+        ("PUT", [chat_id, "settings"]) => {
+            let settings = state.update_settings(chat_id, body)?;
+            Ok(json_response(
+                200,
+                json!({
+                    "status": "success",
+                    "settings": settings
+                }),
+            ))
+        }
+        _ => Ok(error_response(404, "Not Found")),
+    }
+}
+
+fn required_str<'a>(value: &'a Value, field: &str) -> Result<&'a str, ChatError> {
+    value[field]
+        .as_str()
+        .ok_or_else(|| ChatError::Validation(format!("missing string field `{}`", field)))
+}
+
+fn text_response(frames: Vec<Value>) -> WebsocketResponse {
+    WebsocketResponse {
+        messages: frames
+            .into_iter()
+            .map(|frame| WebsocketMessage {
+                ty: MessageType::Text,
+                text: Some(frame.to_string()),
+                data: None,
+            })
+            .collect(),
+    }
+}
+
+fn chat_list_event(state: &State, chat_id: Option<ChatId>) -> ServerEvent {
+    ServerEvent::ChatList {
+        chat_id,
+        chats: state.list_chats(),
+    }
+}
+
+// Events produced by a command besides its reply
+#[derive(Default)]
+struct Outbound {
+    // For the sender, ahead of the reply (such as the stored prompt)
+    events: Vec<ServerEvent>,
+    // For other clients, limited to viewers of the chat when one is given
+    broadcast: Vec<(Option<ChatId>, ServerEvent)>,
+}
+
+// Runs one WebSocket command and returns its reply
+fn handle_command(
+    state: &mut State,
+    envelope: &Envelope,
+    command: ClientCommand,
+    out: &mut Outbound,
+) -> Result<Option<ServerEvent>, ChatError> {
+    // Commands that don't name a chat act on the default one
+    let chat_id = envelope
+        .chat_id
+        .clone()
+        .unwrap_or_else(|| DEFAULT_CHAT_ID.to_string());
+    let origin = Origin {
+        client_id: envelope.client_id.clone(),
+        sent_at: envelope.sent_at,
+    };
+
+    let reply = match command {
+        ClientCommand::Hello { protocol_versions } => ServerEvent::Hello {
+            protocol_version: protocol::negotiate(&protocol_versions)?,
+            protocol_versions: PROTOCOL_VERSIONS,
+        },
+        ClientCommand::Sync => return Ok(None),
+        ClientCommand::SendMessage { content } => {
+            let (user_msg, mut replies) = state.send_message(&chat_id, content, &origin)?;
+            out.events.push(ServerEvent::MessageUpdate {
+                chat_id: chat_id.clone(),
+                messages: vec![user_msg.clone()],
+            });
+            let mut messages = vec![user_msg];
+            messages.extend(replies.iter().cloned());
+            out.broadcast.push((
+                Some(chat_id.clone()),
+                ServerEvent::MessageUpdate {
+                    chat_id: chat_id.clone(),
+                    messages,
+                },
+            ));
+            let ai_msg = replies
+                .pop()
+                .ok_or_else(|| ChatError::provider("No reply was generated"))?;
+            // Tool calls and their results came before the final reply
+            if !replies.is_empty() {
+                out.events.push(ServerEvent::MessageUpdate {
+                    chat_id: chat_id.clone(),
+                    messages: replies,
+                });
+            }
+            ServerEvent::MessageComplete {
+                chat_id,
+                message: Box::new(ai_msg),
+            }
+        }
+        ClientCommand::GetMessages => ServerEvent::MessageUpdate {
+            messages: state.get_message_history(&chat_id)?,
+            chat_id,
+        },
+        ClientCommand::RegenerateMessage { message_id } => {
+            let messages = state.regenerate_message(&chat_id, &message_id, &origin)?;
+            branch_update(state, chat_id, messages, out)?
+        }
+        ClientCommand::EditMessage {
+            message_id,
+            content,
+        } => {
+            let messages = state.edit_message(&chat_id, &message_id, content, &origin)?;
+            branch_update(state, chat_id, messages, out)?
+        }
+        ClientCommand::GetTree => {
+            let messages = state.get_tree(&chat_id)?;
+            let chat = state.chat(&chat_id)?;
+            ServerEvent::Tree {
+                head: chat.head.clone(),
+                leaves: chat.leaves.clone(),
+                chat_id,
+                messages,
+            }
+        }
+        ClientCommand::GetSettings => ServerEvent::Settings {
+            settings: state.chat(&chat_id)?.settings.clone(),
+            chat_id,
+        },
+        ClientCommand::UpdateSettings { settings } => ServerEvent::Settings {
+            settings: state.update_settings(&chat_id, &settings)?,
+            chat_id,
+        },
+        ClientCommand::GetUsage => ServerEvent::Usage {
+            report: state.usage_report(),
+        },
+        ClientCommand::ListChats => chat_list_event(state, None),
+        ClientCommand::CreateChat { title } => {
+            let chat_id = state.create_chat(title.unwrap_or_else(|| "New chat".to_string()));
+            out.broadcast.push((None, chat_list_event(state, None)));
+            chat_list_event(state, Some(chat_id))
+        }
+        ClientCommand::RenameChat { title } => {
+            state.rename_chat(&chat_id, title)?;
+            out.broadcast.push((None, chat_list_event(state, None)));
+            chat_list_event(state, Some(chat_id))
+        }
+        ClientCommand::ImportChat { title, transcript } => {
+            let imported = import::parse(&transcript)?;
+            let (chat_id, _) = state.import_chat(title, imported)?;
+            out.broadcast.push((None, chat_list_event(state, None)));
+            chat_list_event(state, Some(chat_id))
+        }
+        ClientCommand::VerifyChat { repair } => {
+            let report = state.verify_chat(&chat_id, repair)?;
+            if report.repaired {
+                let messages = state.get_message_history(&chat_id)?;
+                let update = branch_update(state, chat_id, messages, out)?;
+                out.events.push(update);
+            }
+            ServerEvent::Verification { report }
+        }
+        ClientCommand::Search { query, limit } => {
+            let results = state.search(&query, limit.unwrap_or(search::DEFAULT_LIMIT))?;
+            ServerEvent::SearchResults { query, results }
+        }
+        ClientCommand::DeleteChat => {
+            state.delete_chat(&chat_id)?;
+            out.broadcast.push((None, chat_list_event(state, None)));
+            chat_list_event(state, None)
+        }
+        // Rejected by `ClientCommand::parse`
+        ClientCommand::Unknown => return Err(ChatError::UnknownCommand(String::new())),
+    };
+
+    Ok(Some(reply))
+}
+
+// Replies with the branch the head moved to and shares it with the chat's other viewers
+fn branch_update(
+    state: &State,
+    chat_id: ChatId,
+    messages: Vec<Message>,
+    out: &mut Outbound,
+) -> Result<ServerEvent, ChatError> {
+    let head = state.chat(&chat_id)?.head.clone();
+    out.broadcast.push((
+        Some(chat_id.clone()),
+        ServerEvent::BranchUpdate {
+            chat_id: chat_id.clone(),
+            head: head.clone(),
+            messages: messages.clone(),
+        },
+    ));
+    Ok(ServerEvent::BranchUpdate {
+        chat_id,
+        head,
+        messages,
+    })
+}
+
 impl WebSocketGuest for Component {
     fn handle_message(msg: WebsocketMessage, state: Json) -> (Json, WebsocketResponse) {
         let mut current_state: State = serde_json::from_slice(&state).unwrap();
+        let mut frames = Vec::new();
+        // Events that only read, such as `sync` polls, hand the state back as it came
+        let mut changed = false;
 
-        match msg.ty {
-            MessageType::Text => {
-                if let Some(text) = msg.text {
-                    if let Ok(command) = serde_json::from_str::<Value>(&text) {
-                        match command["type"].as_str() {
-                            Some("send_message") => {
-                                if let Some(content) = command["content"].as_str() {
-                                    // Create initial user message without ID
-                                    let user_msg = Message::new(
-                                        "user".to_string(),
-                                        content.to_string(),
-                                        current_state.chat.head.clone(),
-                                    );
-
-                                    // Save message and get its ID
-                                    if let Ok(msg_id) = current_state.save_message(&user_msg) {
-                                        if current_state.update_head(msg_id.clone()).is_ok() {
-                                            // Create final message with ID
-                                            let user_msg_with_id = user_msg.with_id(msg_id);
-
-                                            // Get message history for context
-                                            if let Ok(messages) =
-                                                current_state.get_message_history()
-                                            {
-                                                // Generate AI response
-                                                if let Ok(ai_response) =
-                                                    current_state.generate_response(messages)
-                                                {
-                                                    let ai_msg = Message::new(
-                                                        "assistant".to_string(),
-                                                        ai_response,
-                                                        user_msg_with_id.id.clone(),
-                                                    );
-
-                                                    // Save AI message and get its ID
-                                                    if let Ok(ai_msg_id) =
-                                                        current_state.save_message(&ai_msg)
-                                                    {
-                                                        if current_state
-                                                            .update_head(ai_msg_id.clone())
-                                                            .is_ok()
-                                                        {
-                                                            let ai_msg_with_id =
-                                                                ai_msg.with_id(ai_msg_id);
-
-                                                            // Send response with both messages
-                                                            return (
-                                                                serde_json::to_vec(&current_state).unwrap(),
-                                                                WebsocketResponse {
-                                                                    messages: vec![WebsocketMessage {
-                                                                        ty: MessageType::Text,
-                                                                        text: Some(
-                                                                            serde_json::json!({
-                                                                                "type": "message_update",
-                                                                                "messages": [user_msg_with_id, ai_msg_with_id]
-                                                                            })
-                                                                            .to_string(),
-                                                                        ),
-                                                                        data: None,
-                                                                    }],
-                                                                },
-                                                            );
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            Some("get_messages") => {
-                                if let Ok(messages) = current_state.get_message_history() {
-                                    return (
-                                        serde_json::to_vec(&current_state).unwrap(),
-                                        WebsocketResponse {
-                                            messages: vec![WebsocketMessage {
-                                                ty: MessageType::Text,
-                                                text: Some(
-                                                    serde_json::json!({
-                                                        "type": "message_update",
-                                                        "messages": messages
-                                                    })
-                                                    .to_string(),
-                                                ),
-                                                data: None,
-                                            }],
-                                        },
-                                    );
-                                }
-                            }
-                            _ => {
-                                log("Unknown command type received");
-                            }
+        match (msg.ty, msg.text) {
+            (MessageType::Connect, _) => {
+                let client_id = current_state.connect_client();
+                changed = true;
+                log(&format!("Client connected: {}", client_id));
+                frames.push(
+                    ServerEvent::Connected {
+                        client_id,
+                        protocol_versions: PROTOCOL_VERSIONS,
+                    }
+                    .to_frame(None),
+                );
+            }
+            // Clients put their id in the close reason, since the event itself
+            // doesn't say which connection closed; the rest are dropped once idle
+            (MessageType::Close, text) => match text.filter(|id| clients::disconnect(id.trim())) {
+                Some(client_id) => log(&format!("Client disconnected: {}", client_id)),
+                None => log("Unidentified client disconnected"),
+            },
+            (MessageType::Text, Some(text)) => match serde_json::from_str::<Value>(&text) {
+                Ok(value) => {
+                    let envelope = Envelope::from_value(&value);
+                    let request_id = envelope.request_id.as_ref();
+
+                    // Commands carrying a client id first collect that client's queued frames
+                    if let Some(client_id) = &envelope.client_id {
+                        frames.extend(clients::check_in(client_id, envelope.chat_id.as_deref()));
+                    }
+
+                    let mut out = Outbound::default();
+                    let result = ClientCommand::parse(&value).and_then(|command| {
+                        changed = command.changes_state();
+                        handle_command(&mut current_state, &envelope, command, &mut out)
+                    });
+                    frames.extend(out.events.iter().map(|event| event.to_frame(request_id)));
+                    match result {
+                        Ok(reply) => frames.extend(reply.map(|event| event.to_frame(request_id))),
+                        Err(e) => {
+                            log(&format!("Command failed: {}", e));
+                            frames.push(ServerEvent::from(&e).to_frame(request_id));
                         }
                     }
+
+                    for (chat_id, event) in out.broadcast {
+                        clients::broadcast(
+                            envelope.client_id.as_deref(),
+                            chat_id.as_deref(),
+                            &event.to_frame(None),
+                        );
+                    }
                 }
+                Err(e) => frames.push(ServerEvent::from(&ChatError::validation(e)).to_frame(None)),
+            },
+            // Binary frames are uploads; the type is told from the bytes
+            (MessageType::Binary, _) => {
+                let event = msg
+                    .data
+                    .ok_or_else(|| ChatError::validation("Binary frame without data"))
+                    .and_then(|data| current_state.save_attachment(&data, None))
+                    .map(|attachment| ServerEvent::Attachment { attachment })
+                    .unwrap_or_else(|e| {
+                        log(&format!("Upload failed: {}", e));
+                        ServerEvent::from(&e)
+                    });
+                frames.push(event.to_frame(None));
             }
             _ => {}
         }
 
-        (
-            serde_json::to_vec(&current_state).unwrap(),
-            WebsocketResponse { messages: vec![] },
-        )
+        let state = if changed {
+            current_state.to_json()
+        } else {
+            state
+        };
+        (state, text_response(frames))
     }
 }
 
@@ -468,3 +2161,145 @@ impl MessageServerClientGuest for Component {
 }
 
 bindings::export!(Component with_types_in bindings);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        let mut chats = HashMap::new();
+        chats.insert(
+            DEFAULT_CHAT_ID.to_string(),
+            Chat::new("Default".to_string(), None, ChatSettings::default()),
+        );
+        State {
+            chats,
+            next_chat_id: 0,
+            default_settings: ChatSettings::default(),
+            prices: default_prices(),
+            providers: default_providers(),
+            usage: UsageTotals::default(),
+            api_key_file: default_api_key_file(),
+            next_client_id: 0,
+            store: StoreConfig::default(),
+            tools: ToolRegistry::new(),
+            websocket_port: 8080,
+        }
+    }
+
+    #[test]
+    fn serialized_state_never_holds_the_api_key() {
+        let key = "sk-ant-test-0123456789";
+        let state = state();
+        secrets::remember(&state.api_key_file, key.to_string());
+
+        let json = String::from_utf8(state.to_json()).unwrap();
+        assert!(!json.contains(key));
+        assert!(json.contains(&state.api_key_file));
+    }
+
+    #[test]
+    fn the_default_chat_cannot_be_deleted() {
+        let mut state = state();
+        assert!(matches!(
+            state.delete_chat(DEFAULT_CHAT_ID),
+            Err(ChatError::Validation(_))
+        ));
+        assert!(state.chats.contains_key(DEFAULT_CHAT_ID));
+        assert!(matches!(
+            state.delete_chat("chat-1"),
+            Err(ChatError::NotFound(_))
+        ));
+    }
+
+    fn message() -> Message {
+        Message::new(
+            "user".to_string(),
+            Content::from_text("Hello"),
+            Some("abc123".to_string()),
+        )
+    }
+
+    #[test]
+    fn content_id_ignores_the_id_and_key_order() {
+        let msg = message();
+        let id = msg.content_id().unwrap();
+
+        let mut stored = msg.clone();
+        stored.id = Some(id.clone());
+        assert_eq!(stored.content_id().unwrap(), id);
+
+        // Read back from JSON with its keys in another order
+        let reordered =
+            br#"{"parent":"abc123","content":[{"type":"text","text":"Hello"}],"role":"user"}"#;
+        let reread: Message = serde_json::from_slice(reordered).unwrap();
+        assert_eq!(reread.content_id().unwrap(), id);
+
+        let mut edited = msg;
+        edited.content = Content::from_text("Hello!");
+        assert_ne!(edited.content_id().unwrap(), id);
+    }
+
+    #[test]
+    fn decode_accepts_the_canonical_id() {
+        let msg = message();
+        let id = msg.content_id().unwrap();
+        let bytes = serde_json::to_vec(&msg).unwrap();
+
+        let decoded = Message::decode(&id, &bytes).unwrap().unwrap();
+        assert_eq!(decoded.id.as_deref(), Some(id.as_str()));
+        assert_eq!(decoded.content.text(), "Hello");
+    }
+
+    #[test]
+    fn decode_accepts_legacy_ids_hashed_from_the_raw_bytes() {
+        let bytes = b"{ \"role\": \"user\", \"content\": \"Hello\", \"parent\": \"abc123\" }";
+        let id = store::sha1_hex(bytes);
+        assert_ne!(message().content_id().unwrap(), id);
+
+        let decoded = Message::decode(&id, bytes).unwrap().unwrap();
+        assert_eq!(decoded.id.as_deref(), Some(id.as_str()));
+        assert_eq!(decoded.parent.as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn decode_reports_bytes_that_no_longer_match_their_id() {
+        let msg = message();
+        let id = msg.content_id().unwrap();
+        let mut edited = msg;
+        edited.content = Content::from_text("Tampered");
+        let bytes = serde_json::to_vec(&edited).unwrap();
+
+        match Message::decode(&id, &bytes).unwrap() {
+            Err(MessageProblem::HashMismatch { computed, parent }) => {
+                assert_eq!(computed, edited.content_id().unwrap());
+                assert_eq!(parent.as_deref(), Some("abc123"));
+            }
+            other => panic!("expected a hash mismatch, got {:?}", other),
+        }
+
+        assert!(matches!(
+            Message::decode(&id, b"not json").unwrap(),
+            Err(MessageProblem::Unparseable { .. })
+        ));
+    }
+
+    #[test]
+    fn percent_decode_handles_escapes_and_plus_signs() {
+        assert_eq!(percent_decode("a%20b"), "a b");
+        assert_eq!(percent_decode("a+b"), "a b");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("%2b%2B"), "++");
+        assert_eq!(percent_decode("plain"), "plain");
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(percent_decode("%+4"), "% 4");
+        assert_eq!(percent_decode("%-1x"), "%-1x");
+        assert_eq!(percent_decode("%C3"), "\u{fffd}");
+    }
+}
@@ -0,0 +1,187 @@
+use crate::usage::Usage;
+use serde::{Deserialize, Serialize};
+
+// When and how a message came about. Every field is optional, and messages
+// stored before metadata existed have none at all.
+//
+// The actor has no clock of its own: user messages are stamped with the time
+// the client says it sent them, and assistant messages with the `Date` header
+// of the provider's response.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MessageMetadata {
+    // Milliseconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    // Model that wrote an assistant message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    // Why the model stopped, as the provider reports it (e.g. `end_turn`, `max_tokens`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<String>,
+    // From the client sending the prompt to the provider answering; the two
+    // timestamps come from different clocks, so this is approximate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    // WebSocket client the message came from, or that asked for the reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+}
+
+// Where a command came from, for stamping the messages it creates
+#[derive(Debug, Clone, Default)]
+pub struct Origin {
+    pub client_id: Option<String>,
+    // When the client sent the command, in milliseconds since the Unix epoch
+    pub sent_at: Option<u64>,
+}
+
+impl Origin {
+    // Metadata for a message written by the client itself
+    pub fn metadata(&self) -> MessageMetadata {
+        MessageMetadata {
+            created_at: self.sent_at,
+            client_id: self.client_id.clone(),
+            ..MessageMetadata::default()
+        }
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Parses an HTTP date such as "Sun, 06 Nov 1994 08:49:37 GMT" into
+// milliseconds since the Unix epoch
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let mut parts = value.split_whitespace().skip(1);
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month_name = parts.next()?;
+    let month = MONTHS.iter().position(|month| *month == month_name)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts
+        .next()?
+        .split(':')
+        .map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if year < 1970 || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let days = days_since_epoch(year, month, day);
+    Some(((days * 24 + hours) * 60 + minutes) * 60_000 + seconds * 1000)
+}
+
+// Formats milliseconds since the Unix epoch as e.g. "1994-11-06 08:49:37 UTC"
+pub fn format_timestamp(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let (year, month, day) = date_from_days(seconds / 86_400);
+    let time = seconds % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+// Inverse of `days_since_epoch`
+fn date_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
+
+// Days from 1970-01-01 to the given date in the proleptic Gregorian calendar
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Counting years from March puts the leap day at the end of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777_000)
+        );
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2000 00:00:00 GMT"),
+            Some(951_782_400_000)
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 23:59:59 GMT"),
+            Some(1_709_251_199_000)
+        );
+        assert_eq!(
+            parse_http_date("Mon, 01 Mar 2100 00:00:00 GMT"),
+            Some(4_107_542_400_000)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_http_dates() {
+        for value in [
+            "",
+            "Sun, 06 Nov 1994",
+            "Sun, 06 Noc 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Wed, 31 Dec 1969 23:59:59 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(784_111_777_999), "1994-11-06 08:49:37 UTC");
+        assert_eq!(format_timestamp(951_782_400_000), "2000-02-29 00:00:00 UTC");
+        assert_eq!(
+            format_timestamp(4_107_542_400_000 - 1000),
+            "2100-02-28 23:59:59 UTC"
+        );
+    }
+
+    #[test]
+    fn formatted_timestamps_match_the_parsed_date() {
+        for (value, formatted) in [
+            ("Sun, 06 Nov 1994 08:49:37 GMT", "1994-11-06 08:49:37 UTC"),
+            ("Thu, 29 Feb 2024 23:59:59 GMT", "2024-02-29 23:59:59 UTC"),
+            ("Fri, 31 Dec 1999 12:00:00 GMT", "1999-12-31 12:00:00 UTC"),
+            ("Sat, 01 Mar 2025 06:07:08 GMT", "2025-03-01 06:07:08 UTC"),
+        ] {
+            assert_eq!(format_timestamp(parse_http_date(value).unwrap()), formatted);
+        }
+    }
+}
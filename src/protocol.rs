@@ -0,0 +1,237 @@
+use crate::attachments::AttachmentInfo;
+use crate::content::Content;
+use crate::error::ChatError;
+use crate::search::SearchHit;
+use crate::settings::ChatSettings;
+use crate::verify::VerifyReport;
+use crate::{ChatId, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// Protocol versions this actor speaks, oldest first. Clients that never send
+// `hello` get the newest one.
+pub const PROTOCOL_VERSIONS: &[u32] = &[1];
+
+// Fields every command may carry besides its own
+#[derive(Debug, Default)]
+pub struct Envelope {
+    // Opaque id chosen by the client and echoed on every frame answering the command
+    pub request_id: Option<Value>,
+    pub client_id: Option<String>,
+    pub chat_id: Option<ChatId>,
+    // When the client sent the command, in milliseconds since the Unix epoch
+    pub sent_at: Option<u64>,
+}
+
+impl Envelope {
+    // Read leniently, so even a malformed command gets its request id back
+    pub fn from_value(value: &Value) -> Self {
+        Self {
+            request_id: value.get("request_id").filter(|id| !id.is_null()).cloned(),
+            client_id: value["client_id"].as_str().map(str::to_string),
+            chat_id: value["chat_id"].as_str().map(str::to_string),
+            sent_at: value["sent_at"].as_u64(),
+        }
+    }
+}
+
+// Commands sent by WebSocket clients
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    Hello {
+        #[serde(default)]
+        protocol_versions: Vec<u32>,
+    },
+    // Only delivers the frames queued for the client
+    Sync,
+    // `content` is text or an array of text and image blocks
+    SendMessage {
+        content: Content,
+    },
+    GetMessages,
+    RegenerateMessage {
+        message_id: String,
+    },
+    EditMessage {
+        message_id: String,
+        content: Content,
+    },
+    GetTree,
+    GetSettings,
+    UpdateSettings {
+        settings: Value,
+    },
+    GetUsage,
+    ListChats,
+    CreateChat {
+        #[serde(default)]
+        title: Option<String>,
+    },
+    RenameChat {
+        title: String,
+    },
+    DeleteChat,
+    // Creates a chat from a transcript; see `import::parse` for the accepted shapes
+    ImportChat {
+        #[serde(default)]
+        title: Option<String>,
+        transcript: Value,
+    },
+    VerifyChat {
+        // Move the head back to the newest intact message if anything is broken
+        #[serde(default)]
+        repair: bool,
+    },
+    // Searches every chat, not just the one in the envelope
+    Search {
+        query: String,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+impl ClientCommand {
+    pub fn parse(value: &Value) -> Result<Self, ChatError> {
+        match serde_json::from_value(value.clone()) {
+            Ok(Self::Unknown) => Err(ChatError::UnknownCommand(
+                value["type"].as_str().unwrap_or_default().to_string(),
+            )),
+            Ok(command) => Ok(command),
+            Err(e) => Err(ChatError::validation(e)),
+        }
+    }
+
+    // Whether the command can change the actor state. Those that can't leave
+    // it as it was, so polling never has it serialized again.
+    pub fn changes_state(&self) -> bool {
+        !matches!(
+            self,
+            Self::Hello { .. }
+                | Self::Sync
+                | Self::GetMessages
+                | Self::GetTree
+                | Self::GetSettings
+                | Self::GetUsage
+                | Self::ListChats
+                | Self::Search { .. }
+                | Self::VerifyChat { repair: false }
+        )
+    }
+}
+
+// Frames sent to WebSocket clients
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Hello {
+        protocol_version: u32,
+        protocol_versions: &'static [u32],
+    },
+    Connected {
+        client_id: String,
+        protocol_versions: &'static [u32],
+    },
+    MessageUpdate {
+        chat_id: ChatId,
+        messages: Vec<Message>,
+    },
+    MessageComplete {
+        chat_id: ChatId,
+        // Boxed to keep the other events small
+        message: Box<Message>,
+    },
+    BranchUpdate {
+        chat_id: ChatId,
+        head: Option<String>,
+        messages: Vec<Message>,
+    },
+    Tree {
+        chat_id: ChatId,
+        head: Option<String>,
+        leaves: Vec<String>,
+        messages: Vec<Message>,
+    },
+    Settings {
+        chat_id: ChatId,
+        settings: ChatSettings,
+    },
+    Usage {
+        #[serde(flatten)]
+        report: Value,
+    },
+    ChatList {
+        // The chat a command just created or renamed, for the sender to switch to
+        #[serde(skip_serializing_if = "Option::is_none")]
+        chat_id: Option<ChatId>,
+        chats: Vec<Value>,
+    },
+    Verification {
+        #[serde(flatten)]
+        report: VerifyReport,
+    },
+    // Answers a binary frame, with the id to reference the upload by
+    Attachment {
+        #[serde(flatten)]
+        attachment: AttachmentInfo,
+    },
+    SearchResults {
+        query: String,
+        results: Vec<SearchHit>,
+    },
+    Error {
+        code: &'static str,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
+    // The client missed too many updates and should reload everything
+    Resync,
+}
+
+impl ServerEvent {
+    pub fn to_frame(&self, request_id: Option<&Value>) -> Value {
+        let mut frame = serde_json::to_value(self).unwrap_or_else(|e| {
+            json!({
+                "type": "error",
+                "code": "internal_error",
+                "message": e.to_string()
+            })
+        });
+        if let Some(request_id) = request_id {
+            frame["request_id"] = request_id.clone();
+        }
+        frame
+    }
+}
+
+impl From<&ChatError> for ServerEvent {
+    fn from(error: &ChatError) -> Self {
+        Self::Error {
+            code: error.code(),
+            message: error.to_string(),
+            retry_after: error.retry_after(),
+        }
+    }
+}
+
+// Picks the newest version both sides speak
+pub fn negotiate(offered: &[u32]) -> Result<u32, ChatError> {
+    let newest = *PROTOCOL_VERSIONS.last().unwrap_or(&1);
+    if offered.is_empty() {
+        return Ok(newest);
+    }
+    PROTOCOL_VERSIONS
+        .iter()
+        .rev()
+        .find(|version| offered.contains(version))
+        .copied()
+        .ok_or_else(|| {
+            ChatError::UnsupportedProtocol(format!(
+                "offered {:?}, supported {:?}",
+                offered, PROTOCOL_VERSIONS
+            ))
+        })
+}
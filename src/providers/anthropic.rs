@@ -0,0 +1,331 @@
+use super::{response_date, status_error, Completion, LlmProvider};
+use crate::bindings::ntwk::theater::http_client::{send_http, HttpRequest};
+use crate::content::{Content, ContentBlock};
+use crate::error::ChatError;
+use crate::settings::ChatSettings;
+use crate::tools::{ToolChoice, ToolRegistry};
+use crate::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AnthropicMessage {
+    role: String,
+    // Text, or an array of blocks
+    content: Value,
+}
+
+pub struct AnthropicProvider {
+    api_key: String,
+}
+
+impl AnthropicProvider {
+    pub fn new(api_key: String) -> Self {
+        Self { api_key }
+    }
+}
+
+impl LlmProvider for AnthropicProvider {
+    fn generate(
+        &self,
+        settings: &ChatSettings,
+        tools: &ToolRegistry,
+        tool_choice: ToolChoice,
+        messages: &[Message],
+    ) -> Result<Completion, ChatError> {
+        let body = request_body(settings, tools, tool_choice, messages);
+        let request = HttpRequest {
+            method: "POST".to_string(),
+            uri: "https://api.anthropic.com/v1/messages".to_string(),
+            headers: vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("x-api-key".to_string(), self.api_key.clone()),
+                ("anthropic-version".to_string(), "2023-06-01".to_string()),
+            ],
+            body: Some(serde_json::to_vec(&body).map_err(ChatError::provider)?),
+        };
+
+        let http_response = send_http(&request);
+        if http_response.status != 200 {
+            return Err(status_error(&http_response));
+        }
+        let created_at = response_date(&http_response);
+        let body: Value = http_response
+            .body
+            .as_deref()
+            .and_then(|body| serde_json::from_slice(body).ok())
+            .ok_or_else(|| ChatError::provider("Response is not JSON"))?;
+
+        // Text, thinking and tool calls already have this actor's shapes;
+        // redacted thinking has nothing that can be shown or stored
+        let blocks: Vec<ContentBlock> = body["content"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|block| {
+                matches!(
+                    block["type"].as_str(),
+                    Some("text" | "thinking" | "tool_use")
+                )
+            })
+            .filter_map(|block| serde_json::from_value(block.clone()).ok())
+            .collect();
+        let content = Content(blocks);
+        if content.text().is_empty() && content.tool_calls().is_empty() {
+            return Err(ChatError::provider("Response contained no text"));
+        }
+        Ok(Completion {
+            content,
+            usage: serde_json::from_value(body["usage"].clone()).unwrap_or_default(),
+            stop_reason: body["stop_reason"].as_str().map(str::to_string),
+            created_at,
+        })
+    }
+}
+
+fn request_body(
+    settings: &ChatSettings,
+    tools: &ToolRegistry,
+    tool_choice: ToolChoice,
+    messages: &[Message],
+) -> Value {
+    let mut body = json!({
+        "model": settings.model,
+        "max_tokens": settings.max_tokens,
+        "messages": anthropic_messages(messages),
+    });
+
+    let mut definitions: Vec<Value> = tools
+        .iter()
+        .map(|(name, tool)| {
+            json!({
+                "name": name,
+                "description": tool.description,
+                "input_schema": tool.input_schema,
+            })
+        })
+        .collect();
+    // The API refuses tool calls in the history without a definition for
+    // them, so tools removed since they were called are still declared
+    let removed: BTreeSet<&str> = messages
+        .iter()
+        .flat_map(|msg| &msg.content.0)
+        .filter_map(|block| match block {
+            ContentBlock::ToolUse { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .filter(|name| !tools.contains_key(*name))
+        .collect();
+    definitions.extend(removed.into_iter().map(|name| {
+        json!({
+            "name": name,
+            "description": "No longer available",
+            "input_schema": { "type": "object" },
+        })
+    }));
+    if !definitions.is_empty() {
+        body["tools"] = Value::Array(definitions);
+        if tool_choice == ToolChoice::None || tools.is_empty() {
+            body["tool_choice"] = json!({ "type": "none" });
+        }
+    }
+
+    if let Some(temperature) = settings.temperature {
+        body["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = settings.top_p {
+        body["top_p"] = json!(top_p);
+    }
+    if !settings.stop_sequences.is_empty() {
+        body["stop_sequences"] = json!(settings.stop_sequences);
+    }
+    if let Some(system) = &settings.system {
+        body["system"] = json!(system);
+    }
+    body
+}
+
+// Tool results go back on a user turn. Consecutive turns with the same role
+// are merged, since the API expects roles to alternate.
+fn anthropic_messages(messages: &[Message]) -> Vec<AnthropicMessage> {
+    let mut converted: Vec<AnthropicMessage> = Vec::new();
+    for msg in messages {
+        let role = if msg.role == "tool" {
+            "user"
+        } else {
+            &msg.role
+        };
+        let blocks: Vec<Value> = msg.content.0.iter().filter_map(anthropic_block).collect();
+        if blocks.is_empty() {
+            continue;
+        }
+
+        match converted.last_mut() {
+            Some(last) if last.role == role => {
+                if let Value::String(text) = &last.content {
+                    last.content = json!([text_block(text)]);
+                }
+                if let Some(existing) = last.content.as_array_mut() {
+                    existing.extend(blocks);
+                }
+            }
+            _ => {
+                // Plain turns stay plain strings
+                let content = match blocks.as_slice() {
+                    [block] if block["type"] == "text" => block["text"].clone(),
+                    _ => Value::Array(blocks),
+                };
+                converted.push(AnthropicMessage {
+                    role: role.to_string(),
+                    content,
+                });
+            }
+        }
+    }
+    converted
+}
+
+// Stored blocks already follow the Messages API's shapes, apart from the names
+// kept on tool results. Empty text and unsigned thinking would be rejected.
+fn anthropic_block(block: &ContentBlock) -> Option<Value> {
+    match block {
+        ContentBlock::Text { text } if text.is_empty() => None,
+        ContentBlock::Thinking {
+            signature: None, ..
+        } => None,
+        ContentBlock::ToolResult {
+            tool_use_id,
+            content,
+            is_error,
+            ..
+        } => Some(json!({
+            "type": "tool_result",
+            "tool_use_id": tool_use_id,
+            "content": content,
+            "is_error": is_error,
+        })),
+        block => serde_json::to_value(block).ok(),
+    }
+}
+
+fn text_block(text: &str) -> Value {
+    json!({ "type": "text", "text": text })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::ToolConfig;
+
+    fn registry(names: &[&str]) -> ToolRegistry {
+        names
+            .iter()
+            .map(|name| {
+                (
+                    name.to_string(),
+                    ToolConfig {
+                        actor_id: "tool-actor".to_string(),
+                        description: format!("The {} tool", name),
+                        input_schema: json!({ "type": "object" }),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    // A question, a call to `tool` and its result
+    fn tool_history(tool: &str) -> Vec<Message> {
+        vec![
+            Message::new("user".to_string(), Content::from_text("Weather?"), None),
+            Message::new(
+                "assistant".to_string(),
+                Content(vec![ContentBlock::ToolUse {
+                    id: "toolu_1".to_string(),
+                    name: tool.to_string(),
+                    input: json!({ "city": "Oslo" }),
+                }]),
+                None,
+            ),
+            Message::new(
+                "tool".to_string(),
+                Content(vec![ContentBlock::ToolResult {
+                    tool_use_id: "toolu_1".to_string(),
+                    name: tool.to_string(),
+                    content: "Sunny".to_string(),
+                    is_error: false,
+                }]),
+                None,
+            ),
+        ]
+    }
+
+    fn tool_names(body: &Value) -> Vec<&str> {
+        body["tools"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|tool| tool["name"].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn tools_may_be_called_by_default() {
+        let body = request_body(
+            &ChatSettings::default(),
+            &registry(&["weather"]),
+            ToolChoice::Auto,
+            &tool_history("weather"),
+        );
+        assert_eq!(tool_names(&body), ["weather"]);
+        assert!(body.get("tool_choice").is_none());
+    }
+
+    #[test]
+    fn the_last_round_keeps_tools_declared_but_turns_calls_off() {
+        let body = request_body(
+            &ChatSettings::default(),
+            &registry(&["weather"]),
+            ToolChoice::None,
+            &tool_history("weather"),
+        );
+        assert_eq!(tool_names(&body), ["weather"]);
+        assert_eq!(body["tool_choice"], json!({ "type": "none" }));
+        // The result goes back as a user turn answering the call
+        assert_eq!(body["messages"][2]["role"], "user");
+        assert_eq!(body["messages"][2]["content"][0]["type"], "tool_result");
+    }
+
+    #[test]
+    fn removed_tools_in_the_history_are_still_declared() {
+        let body = request_body(
+            &ChatSettings::default(),
+            &ToolRegistry::new(),
+            ToolChoice::Auto,
+            &tool_history("weather"),
+        );
+        assert_eq!(tool_names(&body), ["weather"]);
+        assert_eq!(body["tool_choice"], json!({ "type": "none" }));
+    }
+
+    #[test]
+    fn chats_without_tools_declare_none() {
+        let messages = vec![Message::new(
+            "user".to_string(),
+            Content::from_text("Hello"),
+            None,
+        )];
+        let body = request_body(
+            &ChatSettings::default(),
+            &ToolRegistry::new(),
+            ToolChoice::None,
+            &messages,
+        );
+        assert!(body.get("tools").is_none());
+        assert!(body.get("tool_choice").is_none());
+        assert_eq!(
+            body["messages"],
+            json!([{ "role": "user", "content": "Hello" }])
+        );
+    }
+}
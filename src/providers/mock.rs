@@ -0,0 +1,83 @@
+use super::{Completion, LlmProvider};
+use crate::content::{Content, ContentBlock};
+use crate::error::ChatError;
+use crate::settings::ChatSettings;
+use crate::tools::{ToolChoice, ToolRegistry};
+use crate::usage::Usage;
+use crate::Message;
+use serde_json::Value;
+
+// Echoes the latest user message back, so the whole pipeline can be exercised
+// without an API key. A message of the form `/tool <name> <json input>` calls a
+// registered tool instead, and a tool's output is echoed once it arrives.
+pub struct MockProvider;
+
+impl LlmProvider for MockProvider {
+    fn generate(
+        &self,
+        settings: &ChatSettings,
+        tools: &ToolRegistry,
+        tool_choice: ToolChoice,
+        messages: &[Message],
+    ) -> Result<Completion, ChatError> {
+        let last = messages
+            .iter()
+            .rev()
+            .find(|msg| msg.role == "user" || msg.role == "tool")
+            .ok_or_else(|| ChatError::validation("No user message to respond to"))?;
+
+        if last.role == "user" && tool_choice == ToolChoice::Auto {
+            if let Some(call) = tool_call(tools, &last.content.text(), messages.len()) {
+                return Ok(Completion {
+                    content: Content(vec![call]),
+                    usage: Usage::default(),
+                    stop_reason: Some("tool_use".to_string()),
+                    created_at: None,
+                });
+            }
+        }
+
+        let text = if last.role == "tool" {
+            format!(
+                "[{}] Tool returned: {}",
+                settings.model,
+                last.content.full_text()
+            )
+        } else {
+            format!(
+                "[{}] Message {} received: {}",
+                settings.model,
+                messages.len(),
+                last.content.text()
+            )
+        };
+        // One token per word keeps the accounting predictable
+        let usage = Usage {
+            input_tokens: messages
+                .iter()
+                .map(|msg| msg.content.full_text().split_whitespace().count() as u64)
+                .sum(),
+            output_tokens: text.split_whitespace().count() as u64,
+            ..Usage::default()
+        };
+        Ok(Completion {
+            content: Content::from_text(text),
+            usage,
+            stop_reason: Some("end_turn".to_string()),
+            created_at: None,
+        })
+    }
+}
+
+fn tool_call(tools: &ToolRegistry, prompt: &str, count: usize) -> Option<ContentBlock> {
+    let rest = prompt.strip_prefix("/tool ")?;
+    let (name, input) = rest.trim().split_once(' ').unwrap_or((rest.trim(), "{}"));
+    if !tools.contains_key(name) {
+        return None;
+    }
+    Some(ContentBlock::ToolUse {
+        id: format!("mock-{}", count),
+        name: name.to_string(),
+        input: serde_json::from_str(input).unwrap_or_else(|_| Value::String(input.to_string())),
+    })
+}
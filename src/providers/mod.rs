@@ -0,0 +1,111 @@
+mod anthropic;
+mod mock;
+mod openai;
+
+use crate::bindings::ntwk::theater::http_client::HttpResponse;
+use crate::content::Content;
+use crate::error::ChatError;
+use crate::metadata::parse_http_date;
+use crate::settings::ChatSettings;
+use crate::tools::{ToolChoice, ToolRegistry};
+use crate::usage::Usage;
+use crate::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+pub use anthropic::AnthropicProvider;
+pub use mock::MockProvider;
+pub use openai::OpenAiProvider;
+
+// Backend a chat sends its requests to
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    #[default]
+    Anthropic,
+    // Any server speaking the OpenAI chat-completions API, e.g. llama.cpp or Ollama
+    OpenAi {
+        base_url: String,
+        // File holding the bearer token, if the server wants one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        api_key_file: Option<String>,
+    },
+    // Answers deterministically without any network access
+    Mock,
+}
+
+// Provider profiles by name. Only the init data defines them, so clients can
+// pick where a chat's requests go but never name a URL or key file themselves.
+pub type ProviderTable = HashMap<String, ProviderConfig>;
+
+pub fn default_providers() -> ProviderTable {
+    HashMap::from([
+        ("anthropic".to_string(), ProviderConfig::Anthropic),
+        ("mock".to_string(), ProviderConfig::Mock),
+    ])
+}
+
+// A finished reply along with what it cost
+#[derive(Debug, Clone)]
+pub struct Completion {
+    // Text, thinking and any tools the model wants run before it carries on
+    pub content: Content,
+    pub usage: Usage,
+    pub stop_reason: Option<String>,
+    // When the provider answered, from its `Date` header, in milliseconds since the Unix epoch
+    pub created_at: Option<u64>,
+}
+
+pub trait LlmProvider {
+    // Produces the assistant reply to `messages`. The model may call any of
+    // `tools` unless `tool_choice` says otherwise.
+    fn generate(
+        &self,
+        settings: &ChatSettings,
+        tools: &ToolRegistry,
+        tool_choice: ToolChoice,
+        messages: &[Message],
+    ) -> Result<Completion, ChatError>;
+}
+
+// Maps a non-success HTTP response onto the error clients get to see
+fn status_error(response: &HttpResponse) -> ChatError {
+    let message = response
+        .body
+        .as_deref()
+        .and_then(|body| serde_json::from_slice::<Value>(body).ok())
+        .and_then(|body| body["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("HTTP {}", response.status));
+
+    let retry_after = header(response, "retry-after").and_then(|value| value.parse().ok());
+    match response.status {
+        429 => ChatError::RateLimited {
+            message,
+            retry_after,
+        },
+        // 529 is Anthropic's overloaded status
+        503 | 529 => ChatError::Overloaded {
+            message,
+            retry_after,
+        },
+        500..=599 => ChatError::ServerError {
+            status: response.status,
+            message,
+            retry_after,
+        },
+        status => ChatError::Provider(format!("HTTP {}: {}", status, message)),
+    }
+}
+
+fn response_date(response: &HttpResponse) -> Option<u64> {
+    header(response, "date").and_then(parse_http_date)
+}
+
+fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+    response
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
@@ -0,0 +1,229 @@
+use super::{response_date, status_error, Completion, LlmProvider};
+use crate::bindings::ntwk::theater::http_client::{send_http, HttpRequest};
+use crate::content::{Content, ContentBlock, MediaSource};
+use crate::error::ChatError;
+use crate::settings::ChatSettings;
+use crate::tools::{ToolChoice, ToolRegistry};
+use crate::usage::Usage;
+use crate::Message;
+use serde_json::{json, Value};
+
+pub struct OpenAiProvider {
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl OpenAiProvider {
+    pub fn new(base_url: String, api_key: Option<String>) -> Self {
+        Self { base_url, api_key }
+    }
+}
+
+impl LlmProvider for OpenAiProvider {
+    fn generate(
+        &self,
+        settings: &ChatSettings,
+        tools: &ToolRegistry,
+        tool_choice: ToolChoice,
+        messages: &[Message],
+    ) -> Result<Completion, ChatError> {
+        // The system prompt travels as the first message rather than a separate field
+        let mut openai_messages = Vec::new();
+        if let Some(system) = &settings.system {
+            openai_messages.push(json!({ "role": "system", "content": system }));
+        }
+        openai_messages.extend(messages.iter().flat_map(openai_turns));
+
+        let mut body = json!({
+            "model": settings.model,
+            "max_tokens": settings.max_tokens,
+            "messages": openai_messages,
+        });
+        if let Some(temperature) = settings.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = settings.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if !settings.stop_sequences.is_empty() {
+            body["stop"] = json!(settings.stop_sequences);
+        }
+        if !tools.is_empty() {
+            body["tools"] = tools
+                .iter()
+                .map(|(name, tool)| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": name,
+                            "description": tool.description,
+                            "parameters": tool.input_schema,
+                        },
+                    })
+                })
+                .collect();
+            if tool_choice == ToolChoice::None {
+                body["tool_choice"] = json!("none");
+            }
+        }
+
+        let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+        if let Some(api_key) = &self.api_key {
+            headers.push(("Authorization".to_string(), format!("Bearer {}", api_key)));
+        }
+
+        let request = HttpRequest {
+            method: "POST".to_string(),
+            uri: format!("{}/chat/completions", self.base_url.trim_end_matches('/')),
+            headers,
+            body: Some(serde_json::to_vec(&body).map_err(ChatError::provider)?),
+        };
+
+        let http_response = send_http(&request);
+        if http_response.status != 200 {
+            return Err(status_error(&http_response));
+        }
+        let created_at = response_date(&http_response);
+        let body: Value = http_response
+            .body
+            .as_deref()
+            .and_then(|body| serde_json::from_slice(body).ok())
+            .ok_or_else(|| ChatError::provider("Response is not JSON"))?;
+        if let Some(message) = body["error"]["message"].as_str() {
+            return Err(ChatError::provider(message));
+        }
+
+        let choice = &body["choices"][0];
+        let mut blocks = Vec::new();
+        if let Some(text) = choice["message"]["content"].as_str() {
+            if !text.is_empty() {
+                blocks.push(ContentBlock::Text {
+                    text: text.to_string(),
+                });
+            }
+        }
+        for call in choice["message"]["tool_calls"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            // Arguments arrive as JSON text
+            let arguments = call["function"]["arguments"].as_str().unwrap_or_default();
+            let input = if arguments.is_empty() {
+                json!({})
+            } else {
+                serde_json::from_str(arguments).map_err(|e| {
+                    ChatError::provider(format!("Tool arguments are not JSON: {}", e))
+                })?
+            };
+            blocks.push(ContentBlock::ToolUse {
+                id: call["id"].as_str().unwrap_or_default().to_string(),
+                name: call["function"]["name"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                input,
+            });
+        }
+        if blocks.is_empty() {
+            return Err(ChatError::provider("Response contained no text"));
+        }
+
+        Ok(Completion {
+            content: Content(blocks),
+            usage: Usage {
+                input_tokens: body["usage"]["prompt_tokens"].as_u64().unwrap_or_default(),
+                output_tokens: body["usage"]["completion_tokens"]
+                    .as_u64()
+                    .unwrap_or_default(),
+                ..Usage::default()
+            },
+            stop_reason: choice["finish_reason"].as_str().map(str::to_string),
+            // Local servers often send no `Date` header, but responses carry a
+            // `created` time in seconds
+            created_at: created_at
+                .or_else(|| body["created"].as_u64().map(|seconds| seconds * 1000)),
+        })
+    }
+}
+
+// Tool calls ride on the assistant message, with their arguments as JSON
+// text, and each tool result becomes a `tool` message naming its call.
+// Thinking blocks have no equivalent and are left out.
+fn openai_turns(msg: &Message) -> Vec<Value> {
+    let mut messages = Vec::new();
+    let mut parts = Vec::new();
+    let mut tool_calls = Vec::new();
+    for block in &msg.content.0 {
+        match block {
+            ContentBlock::Text { text } => parts.push(json!({ "type": "text", "text": text })),
+            ContentBlock::Image { source } => {
+                let url = match source {
+                    MediaSource::Base64 { media_type, data } => {
+                        format!("data:{};base64,{}", media_type, data)
+                    }
+                    MediaSource::Url { url } => url.clone(),
+                    // Attachments are resolved before the request is built
+                    MediaSource::Text { .. } | MediaSource::Attachment { .. } => continue,
+                };
+                parts.push(json!({ "type": "image_url", "image_url": { "url": url } }));
+            }
+            // Text files are inlined; PDFs go as files, which not every server reads
+            ContentBlock::Document { source, title } => {
+                let title = title.as_deref().unwrap_or("document");
+                match source {
+                    MediaSource::Text { data, .. } => parts.push(json!({
+                        "type": "text",
+                        "text": format!("{}:\n{}", title, data),
+                    })),
+                    MediaSource::Base64 { media_type, data } => parts.push(json!({
+                        "type": "file",
+                        "file": {
+                            "filename": title,
+                            "file_data": format!("data:{};base64,{}", media_type, data),
+                        },
+                    })),
+                    MediaSource::Url { url } => parts.push(json!({
+                        "type": "text",
+                        "text": format!("{}: {}", title, url),
+                    })),
+                    MediaSource::Attachment { .. } => {}
+                }
+            }
+            ContentBlock::Thinking { .. } => {}
+            ContentBlock::ToolUse { id, name, input } => tool_calls.push(json!({
+                "id": id,
+                "type": "function",
+                "function": {
+                    "name": name,
+                    "arguments": input.to_string(),
+                },
+            })),
+            ContentBlock::ToolResult {
+                tool_use_id,
+                content,
+                ..
+            } => messages.push(json!({
+                "role": "tool",
+                "tool_call_id": tool_use_id,
+                "content": content,
+            })),
+        }
+    }
+    if parts.is_empty() && tool_calls.is_empty() {
+        return messages;
+    }
+
+    // Text alone goes as a plain string, which every server understands
+    let content = match parts.as_slice() {
+        [] => Value::Null,
+        [part] if part["type"] == "text" => part["text"].clone(),
+        _ => Value::Array(parts),
+    };
+    let mut message = json!({ "role": msg.role, "content": content });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = Value::Array(tool_calls);
+    }
+    messages.push(message);
+    messages
+}
@@ -0,0 +1,279 @@
+use crate::error::ChatError;
+use crate::providers::Completion;
+use crate::settings::ChatSettings;
+use serde::{Deserialize, Serialize};
+
+// One try at generating a reply, kept on the assistant message so slow
+// responses can be explained afterwards
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attempt {
+    pub model: String,
+    // Time actually waited before this attempt
+    pub delay_ms: u64,
+    // Error code of a failed attempt, `None` for the one that succeeded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+// Calls `generate` until it succeeds, retrying transient failures on the chat's
+// model and then on the fallback model, if one is configured.
+//
+// `wait` is asked to sleep out each backoff and says whether it could. The
+// actor has no timer import, so in practice it can't, and a failure then moves
+// on to the next model rather than asking the same one again at once. When
+// every model is exhausted the error carries the backoff that was skipped as
+// its `retry_after`, so the client can wait it out instead.
+pub fn with_retry<F>(
+    settings: &ChatSettings,
+    wait: &mut dyn FnMut(u64) -> bool,
+    mut generate: F,
+) -> Result<(Completion, Vec<Attempt>), ChatError>
+where
+    F: FnMut(&ChatSettings) -> Result<Completion, ChatError>,
+{
+    let policy = &settings.retry;
+    let mut models = vec![settings.model.clone()];
+    models.extend(policy.fallback_model.clone());
+
+    let mut attempts = Vec::new();
+    let mut last_error = None;
+    // Backoff the attempt after the last failure should have waited
+    let mut skipped_ms = 0;
+
+    for model in models {
+        let settings = ChatSettings {
+            model: model.clone(),
+            ..settings.clone()
+        };
+        let mut delay_ms = 0;
+
+        for retry in 1..=policy.max_attempts {
+            let e = match generate(&settings) {
+                Ok(completion) => {
+                    attempts.push(Attempt {
+                        model,
+                        delay_ms,
+                        error: None,
+                        message: None,
+                    });
+                    return Ok((completion, attempts));
+                }
+                Err(e) => e,
+            };
+            attempts.push(Attempt {
+                model: model.clone(),
+                delay_ms,
+                error: Some(e.code().to_string()),
+                message: Some(e.to_string()),
+            });
+            if !e.is_retryable() {
+                return Err(e);
+            }
+
+            skipped_ms = policy.delay_ms(retry, e.retry_after());
+            last_error = Some(e);
+            // A `retry-after` longer than the policy allows moves straight on
+            if retry == policy.max_attempts || skipped_ms > policy.max_delay_ms || !wait(skipped_ms)
+            {
+                break;
+            }
+            delay_ms = skipped_ms;
+        }
+    }
+
+    let error = last_error.unwrap_or_else(|| ChatError::provider("No attempts were made"));
+    Err(error.or_retry_after(skipped_ms.div_ceil(1000)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::Content;
+    use crate::settings::RetryPolicy;
+    use crate::usage::Usage;
+
+    fn settings(fallback_model: Option<&str>) -> ChatSettings {
+        let mut settings = ChatSettings {
+            model: "primary".to_string(),
+            ..ChatSettings::default()
+        };
+        settings.retry.fallback_model = fallback_model.map(str::to_string);
+        settings
+    }
+
+    fn completion() -> Completion {
+        Completion {
+            content: Content::from_text("done"),
+            usage: Usage::default(),
+            stop_reason: None,
+            created_at: None,
+        }
+    }
+
+    fn server_error() -> ChatError {
+        ChatError::ServerError {
+            status: 500,
+            message: "boom".to_string(),
+            retry_after: None,
+        }
+    }
+
+    fn rate_limited(retry_after: Option<u64>) -> ChatError {
+        ChatError::RateLimited {
+            message: "slow down".to_string(),
+            retry_after,
+        }
+    }
+
+    // What `with_retry` gave back, with the models it asked and the waits it
+    // requested
+    struct Run {
+        result: Result<(Completion, Vec<Attempt>), ChatError>,
+        models: Vec<String>,
+        waits: Vec<u64>,
+    }
+
+    // Runs `with_retry` against scripted results
+    fn run(
+        settings: &ChatSettings,
+        can_wait: bool,
+        mut results: Vec<Result<Completion, ChatError>>,
+    ) -> Run {
+        results.reverse();
+        let mut models = Vec::new();
+        let mut waits = Vec::new();
+        let result = with_retry(
+            settings,
+            &mut |ms| {
+                waits.push(ms);
+                can_wait
+            },
+            |settings| {
+                models.push(settings.model.clone());
+                results.pop().expect("generate called too often")
+            },
+        );
+        Run {
+            result,
+            models,
+            waits,
+        }
+    }
+
+    #[test]
+    fn server_errors_are_not_retried_at_once_when_waiting_fails() {
+        let Run {
+            result,
+            models,
+            waits,
+        } = run(
+            &settings(Some("fallback")),
+            false,
+            vec![Err(server_error()), Ok(completion())],
+        );
+
+        let (_, attempts) = result.unwrap();
+        assert_eq!(models, ["primary", "fallback"]);
+        assert_eq!(waits, [1000]);
+        assert_eq!(attempts[0].error.as_deref(), Some("provider_error"));
+        assert_eq!(attempts[1].model, "fallback");
+        assert_eq!(attempts[1].delay_ms, 0);
+    }
+
+    #[test]
+    fn an_unwaited_server_error_is_reported_with_its_backoff() {
+        let Run { result, models, .. } = run(&settings(None), false, vec![Err(server_error())]);
+
+        let error = result.unwrap_err();
+        assert!(matches!(error, ChatError::ServerError { .. }));
+        assert_eq!(error.retry_after(), Some(1));
+        assert_eq!(models, ["primary"]);
+    }
+
+    #[test]
+    fn a_rate_limit_moves_to_the_fallback_when_waiting_fails() {
+        let Run { result, models, .. } = run(
+            &settings(Some("fallback")),
+            false,
+            vec![Err(rate_limited(None)), Ok(completion())],
+        );
+
+        let (_, attempts) = result.unwrap();
+        assert_eq!(models, ["primary", "fallback"]);
+        assert_eq!(attempts[0].error.as_deref(), Some("rate_limited"));
+        assert_eq!(attempts[1].model, "fallback");
+    }
+
+    #[test]
+    fn an_unwaited_rate_limit_is_reported_with_its_backoff() {
+        let Run { result, models, .. } = run(&settings(None), false, vec![Err(rate_limited(None))]);
+
+        let error = result.unwrap_err();
+        assert!(matches!(error, ChatError::RateLimited { .. }));
+        assert_eq!(error.retry_after(), Some(1));
+        assert_eq!(models, ["primary"]);
+    }
+
+    #[test]
+    fn waited_backoff_is_recorded_on_each_attempt() {
+        let Run { result, waits, .. } = run(
+            &settings(None),
+            true,
+            vec![Err(server_error()), Err(server_error()), Ok(completion())],
+        );
+
+        let (_, attempts) = result.unwrap();
+        assert_eq!(waits, [1000, 2000]);
+        let delays: Vec<u64> = attempts.iter().map(|attempt| attempt.delay_ms).collect();
+        assert_eq!(delays, [0, 1000, 2000]);
+    }
+
+    #[test]
+    fn a_retry_after_beyond_the_limit_skips_to_the_fallback() {
+        let Run {
+            result,
+            models,
+            waits,
+            ..
+        } = run(
+            &settings(Some("fallback")),
+            true,
+            vec![Err(rate_limited(Some(60))), Ok(completion())],
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(models, ["primary", "fallback"]);
+        assert!(waits.is_empty());
+    }
+
+    #[test]
+    fn errors_that_cannot_be_retried_stop_at_once() {
+        let Run {
+            result,
+            models,
+            waits,
+            ..
+        } = run(
+            &settings(Some("fallback")),
+            true,
+            vec![Err(ChatError::validation("bad request"))],
+        );
+
+        assert!(matches!(result, Err(ChatError::Validation(_))));
+        assert_eq!(models, ["primary"]);
+        assert!(waits.is_empty());
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap_and_honours_retry_after() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay_ms(1, None), 1000);
+        assert_eq!(policy.delay_ms(2, None), 2000);
+        assert_eq!(policy.delay_ms(3, None), 4000);
+        assert_eq!(policy.delay_ms(10, None), 30_000);
+        assert_eq!(policy.delay_ms(1, Some(5)), 5000);
+        assert_eq!(policy.delay_ms(3, Some(1)), 4000);
+    }
+}
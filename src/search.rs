@@ -0,0 +1,287 @@
+use crate::bindings::ntwk::theater::filesystem::{path_exists, read_file, write_file};
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::error::ChatError;
+use crate::ChatId;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Where the index is persisted in the actor's filesystem
+const INDEX_FILE: &str = "data/search-index.json";
+// Results returned when the request doesn't ask for a number
+pub const DEFAULT_LIMIT: usize = 20;
+// Characters of context kept on either side of the first match
+const SNIPPET_BEFORE: usize = 60;
+const SNIPPET_AFTER: usize = 140;
+
+// Inverted index from lowercased words to the messages containing them. The
+// message text itself isn't kept; hits are loaded (usually from the message
+// cache) to build snippets.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SearchIndex {
+    postings: BTreeMap<String, BTreeSet<String>>,
+    // Chats each indexed message was saved in
+    messages: BTreeMap<String, BTreeSet<ChatId>>,
+}
+
+// Loaded from disk (or rebuilt) on first use and kept in instance memory.
+// Changes are written back once per command rather than once per message.
+thread_local! {
+    static INDEX: RefCell<Option<SearchIndex>> = const { RefCell::new(None) };
+    // Whether the index holds changes the file doesn't have yet
+    static DIRTY: Cell<bool> = const { Cell::new(false) };
+}
+
+// One matching message, as returned by `/api/search` and `search_results`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHit {
+    pub chat_id: ChatId,
+    pub message_id: String,
+    pub role: String,
+    pub snippet: String,
+    // Character ranges within `snippet`, end exclusive
+    pub highlights: Vec<[usize; 2]>,
+    // How many times the query's words occur in the message
+    pub score: usize,
+}
+
+// A word and where it sits in the text, in characters
+pub struct Token {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+// Runs of letters and digits, lowercased
+pub fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut current: Option<Token> = None;
+    for (position, c) in text.chars().enumerate() {
+        if c.is_alphanumeric() {
+            let token = current.get_or_insert_with(|| Token {
+                text: String::new(),
+                start: position,
+                end: position,
+            });
+            token.text.extend(c.to_lowercase());
+            token.end = position + 1;
+        } else if let Some(token) = current.take() {
+            tokens.push(token);
+        }
+    }
+    tokens.extend(current);
+    tokens
+}
+
+pub fn is_loaded() -> bool {
+    INDEX.with(|index| index.borrow().is_some())
+}
+
+// Reads the persisted index; false if there is none yet, or none that can be
+// read, in which case it gets rebuilt
+pub fn load() -> bool {
+    if !path_exists(INDEX_FILE).unwrap_or(false) {
+        return false;
+    }
+    let loaded = read_file(INDEX_FILE)
+        .map_err(ChatError::store)
+        .and_then(|bytes| serde_json::from_slice::<SearchIndex>(&bytes).map_err(ChatError::store));
+    match loaded {
+        Ok(loaded) => {
+            INDEX.with(|index| *index.borrow_mut() = Some(loaded));
+            true
+        }
+        Err(e) => {
+            log_failure("load", &e);
+            false
+        }
+    }
+}
+
+impl SearchIndex {
+    pub fn add(&mut self, message_id: &str, chat_id: &str, content: &str) {
+        for token in tokenize(content) {
+            self.postings
+                .entry(token.text)
+                .or_default()
+                .insert(message_id.to_string());
+        }
+        self.messages
+            .entry(message_id.to_string())
+            .or_default()
+            .insert(chat_id.to_string());
+    }
+}
+
+// Replaces the index in memory, e.g. after a rebuild
+pub fn install(rebuilt: SearchIndex) {
+    INDEX.with(|index| *index.borrow_mut() = Some(rebuilt));
+    DIRTY.with(|dirty| dirty.set(true));
+}
+
+pub fn add(message_id: &str, chat_id: &str, content: &str) {
+    INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .get_or_insert_with(SearchIndex::default)
+            .add(message_id, chat_id, content)
+    });
+    DIRTY.with(|dirty| dirty.set(true));
+}
+
+// Writes the index out if it changed since it was last written
+pub fn save() -> Result<(), ChatError> {
+    if !DIRTY.with(Cell::get) {
+        return Ok(());
+    }
+    let content = INDEX.with(|index| {
+        index
+            .borrow()
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(ChatError::store)
+    })?;
+    if let Some(content) = content {
+        write_file(INDEX_FILE, &content).map_err(ChatError::store)?;
+    }
+    DIRTY.with(|dirty| dirty.set(false));
+    Ok(())
+}
+
+// Messages containing every word of the query, with the chats they were saved in
+pub fn find(terms: &[String]) -> Vec<(String, BTreeSet<ChatId>)> {
+    INDEX.with(|index| {
+        let index = index.borrow();
+        let Some(index) = index.as_ref() else {
+            return Vec::new();
+        };
+        let mut postings = terms.iter().map(|term| index.postings.get(term));
+        let Some(Some(first)) = postings.next() else {
+            return Vec::new();
+        };
+        let mut matches: BTreeSet<&String> = first.iter().collect();
+        for posting in postings {
+            let Some(posting) = posting else {
+                return Vec::new();
+            };
+            matches.retain(|id| posting.contains(*id));
+        }
+        matches
+            .into_iter()
+            .map(|id| {
+                let chats = index.messages.get(id).cloned().unwrap_or_default();
+                (id.clone(), chats)
+            })
+            .collect()
+    })
+}
+
+// A stretch of `content` around the first match, with the character ranges
+// of every matched word inside it and how many words matched in all
+pub fn snippet(content: &str, terms: &[String]) -> (String, Vec<[usize; 2]>, usize) {
+    let matched: Vec<Token> = tokenize(content)
+        .into_iter()
+        .filter(|token| terms.contains(&token.text))
+        .collect();
+    let chars: Vec<char> = content.chars().collect();
+    let first = matched.first().map_or(0, |token| token.start);
+    let start = first.saturating_sub(SNIPPET_BEFORE);
+    let end = (first + SNIPPET_AFTER).min(chars.len());
+
+    let mut snippet = String::new();
+    let mut offset = start;
+    if start > 0 {
+        snippet.push('…');
+        // Ranges are relative to the snippet, ellipsis included
+        offset -= 1;
+    }
+    snippet.extend(&chars[start..end]);
+    if end < chars.len() {
+        snippet.push('…');
+    }
+
+    let highlights = matched
+        .iter()
+        .filter(|token| token.start >= start && token.end <= end)
+        .map(|token| [token.start - offset, token.end - offset])
+        .collect();
+    (snippet, highlights, matched.len())
+}
+
+pub fn log_failure(action: &str, error: &ChatError) {
+    log(&format!("Search index {} failed: {}", action, error));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    // The text each highlight range covers, counted in characters
+    fn highlighted(snippet: &str, highlights: &[[usize; 2]]) -> Vec<String> {
+        let chars: Vec<char> = snippet.chars().collect();
+        highlights
+            .iter()
+            .map(|[start, end]| chars[*start..*end].iter().collect())
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_lowercases_and_counts_characters() {
+        let tokens = tokenize("Héllo, WÖRLD 42!");
+        let words: Vec<(&str, usize, usize)> = tokens
+            .iter()
+            .map(|token| (token.text.as_str(), token.start, token.end))
+            .collect();
+        assert_eq!(
+            words,
+            vec![("héllo", 0, 5), ("wörld", 7, 12), ("42", 13, 15)]
+        );
+    }
+
+    #[test]
+    fn snippet_highlights_every_match_without_an_ellipsis() {
+        let (snippet, highlights, score) = snippet("The cat sat on the CAT", &terms(&["cat"]));
+        assert_eq!(snippet, "The cat sat on the CAT");
+        assert_eq!(highlights, vec![[4, 7], [19, 22]]);
+        assert_eq!(score, 2);
+    }
+
+    #[test]
+    fn snippet_ranges_count_the_leading_ellipsis() {
+        let content = format!("{}needle in the haystack", "a ".repeat(50));
+        let (snippet, highlights, score) = snippet(&content, &terms(&["needle"]));
+        assert!(snippet.starts_with('…'));
+        assert_eq!(highlights, vec![[61, 67]]);
+        assert_eq!(highlighted(&snippet, &highlights), vec!["needle"]);
+        assert_eq!(score, 1);
+    }
+
+    #[test]
+    fn snippet_ranges_are_characters_in_multibyte_text() {
+        let content = format!(
+            "{}naïve café, très naïve{}",
+            "é ".repeat(40),
+            " ü".repeat(100)
+        );
+        let (snippet, highlights, score) = snippet(&content, &terms(&["naïve"]));
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert_eq!(highlighted(&snippet, &highlights), vec!["naïve", "naïve"]);
+        assert_eq!(score, 2);
+    }
+
+    #[test]
+    fn snippet_leaves_out_matches_past_its_end() {
+        let content = format!("key {}key", "b ".repeat(100));
+        let (snippet, highlights, score) = snippet(&content, &terms(&["key"]));
+        assert!(!snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert_eq!(highlights, vec![[0, 3]]);
+        assert_eq!(score, 2);
+    }
+}
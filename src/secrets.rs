@@ -0,0 +1,37 @@
+use crate::bindings::ntwk::theater::filesystem::read_file;
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::error::ChatError;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Secrets live only in instance memory, keyed by the file they are read from,
+// so they never end up in the state the runtime persists. After a restart
+// they are read from disk again the first time they are needed.
+thread_local! {
+    static SECRETS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+// Registers a secret that didn't come from disk (e.g. init data) under `path`
+pub fn remember(path: &str, secret: String) {
+    SECRETS.with(|secrets| secrets.borrow_mut().insert(path.to_string(), secret));
+}
+
+pub fn load(path: &str) -> Result<String, ChatError> {
+    if let Some(secret) = SECRETS.with(|secrets| secrets.borrow().get(path).cloned()) {
+        return Ok(secret);
+    }
+
+    log(&format!("Reading secret from {}", path));
+    let bytes = read_file(path)
+        .map_err(|e| ChatError::Provider(format!("Failed to read {}: {}", path, e)))?;
+    let secret = String::from_utf8(bytes)
+        .map_err(|_| ChatError::Provider(format!("{} is not valid UTF-8", path)))?
+        .trim()
+        .to_string();
+    if secret.is_empty() {
+        return Err(ChatError::Provider(format!("{} is empty", path)));
+    }
+
+    remember(path, secret.clone());
+    Ok(secret)
+}
@@ -0,0 +1,222 @@
+use crate::context::ContextStrategy;
+use crate::error::ChatError;
+use crate::providers::{ProviderConfig, ProviderTable};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Model parameters applied to every request made for a chat
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChatSettings {
+    // Name of the provider profile requests go to
+    pub provider: String,
+    pub model: String,
+    pub max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub retry: RetryPolicy,
+    pub context: ContextStrategy,
+    // Model turns per reply that may call tools; with 0 the model can't call any
+    pub max_tool_rounds: u32,
+}
+
+// How transient provider failures (429, 5xx, 529) are retried
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    // Attempts per model, including the first
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // Model to switch to once the primary one has used up its attempts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_model: Option<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 1000,
+            max_delay_ms: 30_000,
+            fallback_model: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Exponential backoff for the given retry (1 for the first retry), capped at
+    // `max_delay_ms`; a longer `retry-after` from the provider wins
+    pub fn delay_ms(&self, retry: u32, retry_after: Option<u64>) -> u64 {
+        let backoff = self
+            .base_delay_ms
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+            .min(self.max_delay_ms);
+        retry_after.map_or(backoff, |seconds| backoff.max(seconds.saturating_mul(1000)))
+    }
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-20241022".to_string(),
+            max_tokens: 1024,
+            temperature: None,
+            top_p: None,
+            stop_sequences: Vec::new(),
+            system: None,
+            retry: RetryPolicy::default(),
+            context: ContextStrategy::Full,
+            max_tool_rounds: 8,
+        }
+    }
+}
+
+impl ChatSettings {
+    // Applies the fields present in `changes`; a null clears an optional field
+    pub fn merge(&self, changes: &Value, providers: &ProviderTable) -> Result<Self, ChatError> {
+        let changes = changes
+            .as_object()
+            .ok_or_else(|| ChatError::validation("settings must be an object"))?;
+        // A provider given inline would let any client send the actor's keys
+        // to a server of its choosing
+        if changes
+            .get("provider")
+            .is_some_and(|provider| !provider.is_string())
+        {
+            return Err(ChatError::validation(
+                "provider must name one of the actor's provider profiles",
+            ));
+        }
+
+        let mut merged = serde_json::to_value(self).map_err(ChatError::validation)?;
+        let fields = merged
+            .as_object_mut()
+            .ok_or_else(|| ChatError::validation("settings must be an object"))?;
+        for (key, value) in changes {
+            fields.insert(key.clone(), value.clone());
+        }
+
+        let settings: Self = serde_json::from_value(merged).map_err(ChatError::validation)?;
+        settings.validate(providers)?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self, providers: &ProviderTable) -> Result<(), ChatError> {
+        let provider = providers.get(&self.provider).ok_or_else(|| {
+            ChatError::Validation(format!("Unknown provider profile {}", self.provider))
+        })?;
+        if let ProviderConfig::OpenAi { base_url, .. } = provider {
+            if base_url.is_empty() {
+                return Err(ChatError::validation("provider base_url must not be empty"));
+            }
+        }
+        if self.model.is_empty() {
+            return Err(ChatError::validation("model must not be empty"));
+        }
+        if self.max_tokens == 0 {
+            return Err(ChatError::validation("max_tokens must be positive"));
+        }
+        if let Some(temperature) = self.temperature {
+            // Anthropic caps temperature at 1, OpenAI-style servers at 2
+            let max = match provider {
+                ProviderConfig::Anthropic => 1.0,
+                _ => 2.0,
+            };
+            if !(0.0..=max).contains(&temperature) {
+                return Err(ChatError::Validation(format!(
+                    "temperature must be between 0 and {}",
+                    max
+                )));
+            }
+        }
+        match self.context {
+            ContextStrategy::LastN { count: 0 } => {
+                return Err(ChatError::validation("context.count must be positive"));
+            }
+            ContextStrategy::TokenBudget { max_tokens: 0 }
+            | ContextStrategy::Summary { max_tokens: 0, .. } => {
+                return Err(ChatError::validation("context.max_tokens must be positive"));
+            }
+            ContextStrategy::Summary { keep_recent: 0, .. } => {
+                return Err(ChatError::validation(
+                    "context.keep_recent must be positive",
+                ));
+            }
+            _ => {}
+        }
+        if self.retry.max_attempts == 0 {
+            return Err(ChatError::validation("retry.max_attempts must be positive"));
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(ChatError::validation("top_p must be between 0 and 1"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::default_providers;
+    use serde_json::json;
+
+    #[test]
+    fn merge_switches_between_provider_profiles_by_name() {
+        let providers = default_providers();
+        let settings = ChatSettings::default()
+            .merge(&json!({"provider": "mock"}), &providers)
+            .unwrap();
+        assert_eq!(settings.provider, "mock");
+
+        assert!(ChatSettings::default()
+            .merge(&json!({"provider": "elsewhere"}), &providers)
+            .is_err());
+    }
+
+    #[test]
+    fn merge_rejects_an_inline_provider() {
+        let changes = json!({
+            "provider": {
+                "type": "open_ai",
+                "base_url": "https://attacker.example",
+                "api_key_file": "api-key.txt"
+            }
+        });
+        assert!(matches!(
+            ChatSettings::default().merge(&changes, &default_providers()),
+            Err(ChatError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn temperature_limit_follows_the_profile() {
+        let mut providers = default_providers();
+        providers.insert(
+            "local".to_string(),
+            ProviderConfig::OpenAi {
+                base_url: "http://localhost:11434/v1".to_string(),
+                api_key_file: None,
+            },
+        );
+        let settings = ChatSettings::default();
+        assert!(settings
+            .merge(&json!({"temperature": 1.5}), &providers)
+            .is_err());
+        assert!(settings
+            .merge(
+                &json!({"provider": "local", "temperature": 1.5}),
+                &providers
+            )
+            .is_ok());
+    }
+}
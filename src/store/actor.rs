@@ -0,0 +1,227 @@
+use super::{MessageStore, StoredEntry};
+use crate::bindings::ntwk::theater::message_server_host::request;
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::error::ChatError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+// Import the Request/Action types - we'll need to define these since we can't import from store actor
+#[derive(Serialize, Deserialize, Debug)]
+struct Request {
+    _type: String,
+    data: Action,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Action {
+    Get(String),
+    Put(Vec<u8>),
+    All(()),
+    // Answered with `values`, one byte array (or null) per key
+    GetMany(Vec<String>),
+    // Answered with `entries` of `key` and `value`, starting at the given key
+    // and following each message's `parent`
+    GetAncestry(String),
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Get(_) => "Get",
+            Self::Put(_) => "Put",
+            Self::All(_) => "All",
+            Self::GetMany(_) => "GetMany",
+            Self::GetAncestry(_) => "GetAncestry",
+        }
+    }
+}
+
+// Batch actions the store actor turned down, so they aren't tried again until
+// the next restart
+thread_local! {
+    static UNSUPPORTED: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+}
+
+// Messages kept by a separate key-value store actor
+pub struct ActorStore {
+    store_id: String,
+}
+
+impl ActorStore {
+    pub fn new(store_id: String) -> Self {
+        Self { store_id }
+    }
+
+    fn send(&self, action: Action) -> Result<Value, ChatError> {
+        let req = Request {
+            _type: "request".to_string(),
+            data: action,
+        };
+
+        let request_bytes = serde_json::to_vec(&req).map_err(ChatError::store)?;
+        let response_bytes = request(&self.store_id, &request_bytes).map_err(ChatError::store)?;
+
+        serde_json::from_slice(&response_bytes).map_err(ChatError::store)
+    }
+
+    // Sends an action older store actors may not know; `None` means fall back
+    // to plain `Get`s. Only an explicit rejection of the action is remembered;
+    // failing to reach the store is an error like any other.
+    fn send_batch(&self, action: Action) -> Result<Option<Value>, ChatError> {
+        let name = action.name();
+        if UNSUPPORTED.with(|unsupported| unsupported.borrow().contains(name)) {
+            return Ok(None);
+        }
+        let response = self.send(action)?;
+        if response["status"].as_str() == Some("ok") {
+            return Ok(Some(response));
+        }
+        if !rejects_action(&response, name) {
+            return Err(ChatError::store(format!(
+                "{} failed: {}",
+                name,
+                error_message(&response)
+            )));
+        }
+        log(&format!(
+            "Store actor doesn't support {} ({}); falling back to Get",
+            name,
+            error_message(&response)
+        ));
+        UNSUPPORTED.with(|unsupported| unsupported.borrow_mut().insert(name));
+        Ok(None)
+    }
+}
+
+fn error_message(response: &Value) -> String {
+    ["message", "error"]
+        .iter()
+        .find_map(|field| response[field].as_str())
+        .map_or_else(|| response["status"].to_string(), str::to_string)
+}
+
+// Error code a store actor answers an action it doesn't implement with
+const UNSUPPORTED_ACTION: &str = "unsupported_action";
+
+// Whether a failed response says the store actor doesn't know `name`: either
+// the agreed error code, or the request parse failure of a store actor built
+// before the action existed, which names the action as an unknown variant.
+// Failures of a known action, such as a missing key, don't count.
+fn rejects_action(response: &Value, name: &str) -> bool {
+    response["code"].as_str() == Some(UNSUPPORTED_ACTION)
+        || error_message(response).contains(&format!("unknown variant `{}`", name))
+}
+
+fn bytes(value: &Value) -> Result<Vec<u8>, ChatError> {
+    Ok(value
+        .as_array()
+        .ok_or_else(|| ChatError::store("Expected byte array"))?
+        .iter()
+        .map(|v| v.as_u64().unwrap_or(0) as u8)
+        .collect())
+}
+
+impl MessageStore for ActorStore {
+    // The store actor picks the key itself, which has to agree with ours
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), ChatError> {
+        let response = self.send(Action::Put(bytes.to_vec()))?;
+        if response["status"].as_str() != Some("ok") {
+            return Err(ChatError::store("Failed to save message"));
+        }
+        match response["key"].as_str() {
+            Some(stored) if stored == key => Ok(()),
+            Some(stored) => Err(ChatError::store(format!(
+                "Store actor keyed message {} as {}",
+                key, stored
+            ))),
+            None => Err(ChatError::store("No key in response")),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ChatError> {
+        let response = self.send(Action::Get(key.to_string()))?;
+        if response["status"].as_str() != Some("ok") {
+            return Err(ChatError::store(format!("Failed to load message {}", key)));
+        }
+        match response.get("value").filter(|value| !value.is_null()) {
+            // The value should be an array of bytes that we can directly deserialize
+            Some(value) => bytes(value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, ChatError> {
+        let Some(response) = self.send_batch(Action::GetMany(keys.to_vec()))? else {
+            return keys.iter().map(|key| self.get(key)).collect();
+        };
+        let values = response["values"]
+            .as_array()
+            .filter(|values| values.len() == keys.len())
+            .ok_or_else(|| ChatError::store("Expected one value per key"))?;
+        values
+            .iter()
+            .map(|value| match value {
+                Value::Null => Ok(None),
+                value => bytes(value).map(Some),
+            })
+            .collect()
+    }
+
+    fn get_ancestry(&self, head: &str) -> Result<Option<Vec<StoredEntry>>, ChatError> {
+        let Some(response) = self.send_batch(Action::GetAncestry(head.to_string()))? else {
+            return Ok(None);
+        };
+        let entries = response["entries"]
+            .as_array()
+            .ok_or_else(|| ChatError::store("Expected ancestry entries"))?;
+        entries
+            .iter()
+            .map(|entry| {
+                let key = entry["key"]
+                    .as_str()
+                    .ok_or_else(|| ChatError::store("No key in ancestry entry"))?;
+                Ok((key.to_string(), bytes(&entry["value"])?))
+            })
+            .collect::<Result<Vec<_>, ChatError>>()
+            .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn unknown_actions_are_recognised() {
+        let coded = json!({
+            "status": "error",
+            "code": "unsupported_action",
+            "message": "GetMany is not implemented"
+        });
+        assert!(rejects_action(&coded, "GetMany"));
+
+        // What serde says when a store built before the action parses it
+        let unparsed = json!({
+            "status": "error",
+            "message": "unknown variant `GetAncestry`, expected one of `Get`, `Put`, `All`"
+        });
+        assert!(rejects_action(&unparsed, "GetAncestry"));
+        assert!(!rejects_action(&unparsed, "GetMany"));
+    }
+
+    #[test]
+    fn failures_of_a_supported_action_are_not_rejections() {
+        for response in [
+            json!({ "status": "error", "message": "unknown key abc123" }),
+            json!({ "status": "error", "message": "GetAncestry failed: key not found" }),
+            json!({ "status": "error", "error": "unsupported value in entry" }),
+            json!({ "status": "error", "code": "not_found" }),
+            json!({ "status": "error" }),
+        ] {
+            assert!(!rejects_action(&response, "GetAncestry"), "{}", response);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use super::MessageStore;
+use crate::bindings::ntwk::theater::filesystem::{create_dir, path_exists, read_file, write_file};
+use crate::error::ChatError;
+
+// Messages kept as `<dir>/<id>.json`
+pub struct FileStore {
+    dir: String,
+}
+
+impl FileStore {
+    pub fn new(dir: String) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, key: &str) -> Result<String, ChatError> {
+        // Keys end up in file paths, so only accept what `put` hands out
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ChatError::validation(format!("Invalid message id {}", key)));
+        }
+        Ok(format!("{}/{}.json", self.dir, key))
+    }
+
+    // Creates the message directory and any missing parents
+    fn ensure_dir(&self) -> Result<(), ChatError> {
+        if path_exists(&self.dir).map_err(ChatError::store)? {
+            return Ok(());
+        }
+        let mut path = String::new();
+        for component in self.dir.split('/').filter(|c| !c.is_empty()) {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(component);
+            if !path_exists(&path).map_err(ChatError::store)? {
+                create_dir(&path).map_err(ChatError::store)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageStore for FileStore {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), ChatError> {
+        let path = self.path(key)?;
+        // Ids are content hashes, so an existing file already holds these bytes
+        if path_exists(&path).map_err(ChatError::store)? {
+            return Ok(());
+        }
+
+        let content = std::str::from_utf8(bytes).map_err(ChatError::store)?;
+        self.ensure_dir()?;
+        write_file(&path, content).map_err(ChatError::store)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ChatError> {
+        let path = self.path(key)?;
+        if !path_exists(&path).map_err(ChatError::store)? {
+            return Ok(None);
+        }
+        read_file(&path).map(Some).map_err(ChatError::store)
+    }
+}
@@ -0,0 +1,72 @@
+mod actor;
+mod filesystem;
+
+use crate::error::ChatError;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+pub use actor::ActorStore;
+pub use filesystem::FileStore;
+
+// Where messages are persisted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StoreConfig {
+    // A key-value store actor reached through the message server
+    Actor {
+        store_id: String,
+    },
+    // Content-addressed JSON files in the actor's own filesystem
+    Filesystem {
+        #[serde(default = "default_message_dir")]
+        dir: String,
+    },
+}
+
+fn default_message_dir() -> String {
+    "data/messages".to_string()
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self::Filesystem {
+            dir: default_message_dir(),
+        }
+    }
+}
+
+impl StoreConfig {
+    pub fn backend(&self) -> Box<dyn MessageStore> {
+        match self {
+            Self::Actor { store_id } => Box::new(ActorStore::new(store_id.clone())),
+            Self::Filesystem { dir } => Box::new(FileStore::new(dir.clone())),
+        }
+    }
+}
+
+// Hex SHA-1 of `bytes`, the form every message id takes
+pub fn sha1_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(bytes))
+}
+
+// A key and the bytes stored under it
+pub type StoredEntry = (String, Vec<u8>);
+
+pub trait MessageStore {
+    // Persists a serialized message under `key`, its content-addressed id
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), ChatError>;
+
+    // The bytes stored under `key`, or `None` if there are none
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ChatError>;
+
+    // Several keys at once, in the order asked for
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, ChatError> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    // The message under `head` followed by as many of its ancestors as the store
+    // walks in one go, newest first, or `None` if it can't walk them itself
+    fn get_ancestry(&self, _head: &str) -> Result<Option<Vec<StoredEntry>>, ChatError> {
+        Ok(None)
+    }
+}
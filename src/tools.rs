@@ -0,0 +1,107 @@
+use crate::bindings::ntwk::theater::message_server_host::request;
+use crate::error::ChatError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+// Tools offered to the model, keyed by the name it calls them by
+pub type ToolRegistry = BTreeMap<String, ToolConfig>;
+
+// Whether the model may call the tools it is offered. Tools stay on offer even
+// when it may not, since providers refuse a history holding tool calls and
+// results from a request that defines no tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolChoice {
+    Auto,
+    None,
+}
+
+// A tool implemented by another actor
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolConfig {
+    // Actor that receives the calls
+    pub actor_id: String,
+    #[serde(default)]
+    pub description: String,
+    // JSON Schema for the tool's input
+    #[serde(default = "default_input_schema")]
+    pub input_schema: Value,
+}
+
+fn default_input_schema() -> Value {
+    json!({ "type": "object" })
+}
+
+// Providers only accept short names of letters, digits, `_` and `-`
+pub fn validate_name(name: &str) -> Result<(), ChatError> {
+    let valid = (1..=64).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(ChatError::validation(format!(
+            "Tool name {:?} must be 1-64 letters, digits, _ or -",
+            name
+        )));
+    }
+    Ok(())
+}
+
+// A tool the model asked to run, stored on the assistant message that asked
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: Value,
+}
+
+// Sent to the tool's actor
+#[derive(Serialize, Debug)]
+struct ToolRequest<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: &'a str,
+    name: &'a str,
+    input: &'a Value,
+}
+
+// What the actor answers with: the output, as text or any JSON, and whether it
+// describes a failure
+#[derive(Deserialize, Debug)]
+struct ToolResponse {
+    #[serde(default)]
+    content: Value,
+    #[serde(default)]
+    is_error: bool,
+}
+
+// Runs `call` on the actor behind it. Failures are reported back to the model
+// as an error result rather than ending the turn, so it can try another way.
+pub fn run(registry: &ToolRegistry, call: &ToolCall) -> (String, bool) {
+    let Some(tool) = registry.get(&call.name) else {
+        return (format!("Unknown tool: {}", call.name), true);
+    };
+    match invoke(tool, call) {
+        Ok(response) => {
+            let content = match response.content {
+                Value::String(text) => text,
+                Value::Null => String::new(),
+                other => other.to_string(),
+            };
+            (content, response.is_error)
+        }
+        Err(e) => (format!("Tool {} failed: {}", call.name, e), true),
+    }
+}
+
+fn invoke(tool: &ToolConfig, call: &ToolCall) -> Result<ToolResponse, ChatError> {
+    let req = ToolRequest {
+        kind: "tool_call",
+        id: &call.id,
+        name: &call.name,
+        input: &call.input,
+    };
+    let request_bytes = serde_json::to_vec(&req).map_err(ChatError::provider)?;
+    let response_bytes = request(&tool.actor_id, &request_bytes).map_err(ChatError::provider)?;
+    serde_json::from_slice(&response_bytes).map_err(ChatError::provider)
+}
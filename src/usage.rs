@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Token counts reported by the provider for one response
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+}
+
+impl Usage {
+    pub fn cost(&self, price: &ModelPrice) -> f64 {
+        (self.input_tokens as f64 * price.input
+            + self.output_tokens as f64 * price.output
+            + self.cache_creation_input_tokens as f64 * price.cache_write
+            + self.cache_read_input_tokens as f64 * price.cache_read)
+            / 1_000_000.0
+    }
+}
+
+// USD per million tokens
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+    pub cache_write: f64,
+    pub cache_read: f64,
+}
+
+pub type PriceTable = HashMap<String, ModelPrice>;
+
+pub fn default_prices() -> PriceTable {
+    HashMap::from([(
+        "claude-3-5-sonnet-20241022".to_string(),
+        ModelPrice {
+            input: 3.0,
+            output: 15.0,
+            cache_write: 3.75,
+            cache_read: 0.3,
+        },
+    )])
+}
+
+// Running totals for a chat or for the whole actor
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct UsageTotals {
+    #[serde(flatten)]
+    pub usage: Usage,
+    pub cost_usd: f64,
+    pub responses: u64,
+    // Tokens from models missing from the price table, which count as free
+    pub unpriced_responses: u64,
+}
+
+impl UsageTotals {
+    pub fn record(&mut self, usage: &Usage, price: Option<&ModelPrice>) {
+        self.usage.input_tokens += usage.input_tokens;
+        self.usage.output_tokens += usage.output_tokens;
+        self.usage.cache_creation_input_tokens += usage.cache_creation_input_tokens;
+        self.usage.cache_read_input_tokens += usage.cache_read_input_tokens;
+        self.responses += 1;
+        match price {
+            Some(price) => self.cost_usd += usage.cost(price),
+            None => self.unpriced_responses += 1,
+        }
+    }
+}
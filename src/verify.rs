@@ -0,0 +1,44 @@
+use crate::ChatId;
+use serde::Serialize;
+
+// Ways a stored message can be unusable
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum MessageProblem {
+    // Nothing is stored under the id
+    Missing,
+    // The stored bytes aren't a message
+    Unparseable {
+        error: String,
+    },
+    // The message no longer hashes to its id
+    HashMismatch {
+        computed: String,
+        // Still followed, so the rest of the chain gets checked
+        #[serde(skip)]
+        parent: Option<String>,
+    },
+    // The message is its own ancestor
+    Cycle,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Issue {
+    pub message_id: String,
+    #[serde(flatten)]
+    pub problem: MessageProblem,
+}
+
+// Outcome of walking a chat's current branch from the head back to the root
+#[derive(Serialize, Debug, Clone)]
+pub struct VerifyReport {
+    pub chat_id: ChatId,
+    pub head: Option<String>,
+    pub ok: bool,
+    pub messages_checked: usize,
+    pub issues: Vec<Issue>,
+    // Newest message on the branch whose whole ancestry checked out
+    pub last_good: Option<String>,
+    // Whether the head was moved to `last_good`
+    pub repaired: bool,
+}